@@ -1,323 +1,2224 @@
-use std::collections::HashMap;
-use std::time::SystemTime;
+extern crate carrotson;
+
+mod http;
+mod json;
+
+use std::time::{Duration, SystemTime};
 use std::fs;
 use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::net::TcpListener;
 use std::env;
+use std::thread;
 use std::process::exit;
+use std::str::FromStr;
+use std::convert::TryFrom;
+use carrotson::{LCG, Freq, Model, SuffixIndex, Slicer, GenConfig, TrainConfig, WordModel, CodepointModel, ModelKind, ModelInfo, Metadata, Preprocessing, MAX_ORDER, MAX_WORD_ORDER, MODEL_VERSION_LEGACY, context_bytes, context_mask, context_push, generate_streaming, generate_words, generate_codepoints, read_model_info};
+use json::Json;
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes.iter().flat_map(|x| std::ascii::escape_default(*x)).map(|x| x as char).collect()
+}
 
-struct LCG {
-    state: u64
+// Replaces \n, \t, \0 and \\ with the characters they stand for, so the special characters
+// can be passed through the command line easily
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some('\\') => result.push('\\'),
+            Some(c) => {
+                result.push('\\');
+                result.push(c);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
 }
 
-impl LCG {
-    fn new(seed: u64) -> Self {
-        Self {state: seed}
+struct Subcommand {
+    name: &'static str,
+    signature: &'static str,
+    description: &'static str,
+    flags: &'static [(&'static str, &'static str)],
+}
+
+// Shared by `gen` and `repl`
+const GEN_FLAGS: &[(&str, &str)] = &[
+    ("-l, --limit <LIMIT>", "maximum amount of bytes to generate, or words and codepoints for the models trained with `train --words` and `train --codepoints` (default: 1024). 0 means no limit, implies --stream"),
+    ("--infinite", "same as --limit 0: keep generating until interrupted"),
+    ("--words <N>", "stop after <N> words separated by whitespace. --limit still applies and defaults to 64 bytes per word with this"),
+    ("--prompt <TEXT>", "start the text with <TEXT> and continue it from there"),
+    ("--random-start", "start from a random context of the model instead of the beginning of the text. Can't be combined with --prompt"),
+    ("--stream", "print the text as it's being generated instead of all at once at the end"),
+    ("-q, --quiet", "don't print the status messages to stderr, only the generated text to stdout"),
+    ("-o, --output <FILE>", "write the generated bytes as they are to <FILE> instead of stdout"),
+    ("--count <N>", "generate <N> separate samples (default: 1)"),
+    ("--separator <STRING>", "print <STRING> between the samples of --count. Understands the same escapes as --stop (default: \\n)"),
+    ("--diverse-starts", "make every sample of --count less likely to start with the same byte as the earlier ones. Can't be combined with --beam, --flatten-counts or --smoothing"),
+    ("--seed <SEED>", "seed of the random generator, to reproduce a particular sample (default: current time)"),
+    ("--order <N>", "fail unless the model has order <N>. The order always comes from the model, this only guards against using the wrong one"),
+    ("--greedy", "always pick the most frequent continuation instead of sampling. Can't be combined with --flatten-counts, --temperature or --smoothing"),
+    ("--beam <W>", "instead of sampling, look for the most probable text keeping <W> candidates at every step (beam search). Only --prompt, --limit, --stop, --trim-stop and --eos are respected"),
+    ("--flatten-counts", "ignore the learned frequencies and pick uniformly among the known continuations of each context. Dramatically increases weirdness"),
+    ("--temperature <T>", "reshape the distribution: below 1.0 prefers the most frequent continuations, above 1.0 flattens toward uniform (default: 1.0). 0.0 always picks the most frequent one, same as --greedy"),
+    ("--smoothing <K>", "add <K> to the count of every possible byte, so even the unseen ones may be picked. Can't be combined with --flatten-counts, --temperature or --utf8-boundary"),
+    ("--repetition-penalty <F>", "divide the weights of the bytes among the last 32 bytes of the text by <F>, so values above 1.0 discourage loops. Can't be combined with --flatten-counts, --smoothing or --greedy"),
+    ("--top-k <K>", "sample only among the <K> most frequent continuations of each context"),
+    ("--top-p <P>", "sample only among the most frequent continuations that together have probability of at least <P>, 0.0..=1.0. Can't be combined with --top-k"),
+    ("--stop <STRING>", "stop as soon as the generated text ends with <STRING>. Understands \\n, \\t, \\0 and \\\\ escapes"),
+    ("--trim-stop", "don't include the --stop string into the output"),
+    ("--min-length <N>", "when a context dead-ends before <N> bytes were generated, continue from a similar context instead of stopping"),
+    ("--no-backoff", "stop as soon as the context has no continuations instead of falling back to its shorter suffixes"),
+    ("--eos", "stop at the end of a text, as learned by `train --eos`"),
+    ("--no-cycle-guard", "don't jump to a different context when the text keeps repeating the same few bytes, like `ha ha ha ...`"),
+    ("--utf8-boundary", "never emit a byte that would start or continue a UTF-8 sequence that can't be completed, resample instead"),
+];
+
+const SUBCOMMANDS: &[Subcommand] = &[
+    Subcommand {
+        name: "train",
+        signature: "train <INPUT>... <OUTPUT>",
+        description: "generate binary model file <OUTPUT> based on all the <INPUT> files (`-` for stdin)",
+        flags: &[
+            ("--order <N>", "amount of preceding bytes the model uses as the context, 1..=8 (default: 8)"),
+            ("--per-line", "treat every line as an independent text, so contexts don't span across lines"),
+            ("--skip-warmup", "don't learn the first --order bytes of the text (or of every line with --per-line), which don't have a full context yet. `gen` then starts from a random context, as with --random-start"),
+            ("--words", "model whole words separated by whitespace instead of bytes. The order is then counted in words, 1..=3 (default: 2). Trains on a single thread and can't be combined with --per-line"),
+            ("--codepoints", "model Unicode codepoints instead of bytes, so `gen` never produces broken UTF-8. The order is then counted in codepoints, 1..=3 (default: 3). Trains on a single thread and can't be combined with --per-line or --words"),
+            ("--lowercase", "lowercase the ASCII letters of the text before training, or all of them with --codepoints. Recorded in the model, so the prompts are lowercased too"),
+            ("--normalize-ws", "collapse the runs of whitespace within the lines into a single space and drop the trailing whitespace of every line before training. Recorded in the model like --lowercase"),
+            ("--strip-control", "drop the control bytes 0x00..=0x1F other than \\n and \\t before training, like the stray ESC of the ANSI escape sequences or the bells. Recorded in the model like --lowercase"),
+            ("--bos", "with --per-line, pad the contexts at the beginning of every line with byte 0xFF instead of zeros, so they are not confused with a real run of zero bytes. `gen` starts from that context"),
+            ("--eos", "with --per-line, also learn where every line ends, for `gen --eos`"),
+            ("--weight <N>", "count every byte of the <INPUT> files that follow this flag <N> times, so a small text can outweigh a bigger one (default: 1)"),
+            ("--threads <N>", "amount of threads to train on (default: amount of available CPUs)"),
+            ("--max-records <N>", "stop adding new contexts once the model has <N> of them, but keep counting the known ones. Trains on a single thread"),
+            ("--max-branching <K>", "keep only <K> most frequent continuations of every context, which makes the model smaller at the cost of the rare ones"),
+            ("--continue <MODEL>", "keep training the existing <MODEL> instead of starting from scratch"),
+        ],
+    },
+    Subcommand {
+        name: "gen",
+        signature: "gen <FILE>",
+        description: "generate random text based on a model trained from <FILE>",
+        flags: GEN_FLAGS,
+    },
+    Subcommand {
+        name: "repl",
+        signature: "repl <FILE>",
+        description: "load the model <FILE> once and continue every line of stdin as a prompt. The lines `/seed <SEED>`, `/limit <LIMIT>` and `/quit` change the options of `gen` instead",
+        flags: GEN_FLAGS,
+    },
+    Subcommand {
+        name: "serve",
+        signature: "serve <FILE>",
+        description: "load the model <FILE> once and answer `GET /gen?prompt=<TEXT>&limit=<LIMIT>&seed=<SEED>` with generated text over HTTP. The options of `gen` are the defaults for every request, and --limit is also the most a request may ask for",
+        flags: &[
+            ("--host <ADDRESS>", "address to listen on (default: 127.0.0.1)"),
+            ("--port <PORT>", "port to listen on (default: 8080)"),
+        ],
+    },
+    Subcommand {
+        name: "query",
+        signature: "query <FILE> <PREFIX>",
+        description: "print the most likely continuations of <PREFIX> anywhere in the text according to the model <FILE>, falling back to the shorter suffixes of <PREFIX> if the model has never seen it",
+        flags: &[
+            ("--format <FORMAT>", "output format: `text` or `md` for a Markdown table (default: text)"),
+            ("--top <N>", "print only the <N> most likely continuations"),
+        ],
+    },
+    Subcommand {
+        name: "complete",
+        signature: "complete <FILE> <PREFIX>",
+        description: "print the <N> most likely next bytes after <PREFIX> according to the model <FILE>, falling back to the shorter suffixes of <PREFIX> if the model has never seen it",
+        flags: &[
+            ("--n <N>", "amount of the next bytes to print (default: 5)"),
+        ],
+    },
+    Subcommand {
+        name: "score",
+        signature: "score <FILE> <TEXT>",
+        description: "measure how well the model <FILE> predicts the file <TEXT> (`-` for stdin): the average negative log-likelihood and the perplexity",
+        flags: &[
+            ("--floor <P>", "probability of the bytes the model has never seen in their context (default: 0.000001)"),
+            ("--backoff", "before resorting to --floor, look at the shorter suffixes of the context as `gen` does, and break the perplexity down by how many times each byte had to back off"),
+        ],
+    },
+    Subcommand {
+        name: "merge",
+        signature: "merge <A> <B> <OUTPUT>",
+        description: "combine the models <A> and <B> into a single model file <OUTPUT>",
+        flags: &[],
+    },
+    Subcommand {
+        name: "diff",
+        signature: "diff <A> <B>",
+        description: "compare the models <A> and <B>: which contexts only one of them knows and which frequencies changed",
+        flags: &[
+            ("--top <N>", "amount of the largest frequency differences to print (default: 10)"),
+        ],
+    },
+    Subcommand {
+        name: "prune",
+        signature: "prune <INPUT> <OUTPUT>",
+        description: "drop the rare continuations of <INPUT> and save the result to <OUTPUT>. At least one of the flags is required",
+        flags: &[
+            ("--min-count <K>", "minimal amount of times a continuation has to be seen to be kept"),
+            ("--max-branching <K>", "keep only <K> most frequent continuations of every context"),
+        ],
+    },
+    Subcommand {
+        name: "export-json",
+        signature: "export-json <INPUT> <OUTPUT>",
+        description: "save the model <INPUT> as JSON file <OUTPUT> for inspection and other tools",
+        flags: &[],
+    },
+    Subcommand {
+        name: "import-json",
+        signature: "import-json <INPUT> <OUTPUT>",
+        description: "convert JSON file <INPUT> in the format of export-json to binary model file <OUTPUT>",
+        flags: &[],
+    },
+    Subcommand {
+        name: "export-csv",
+        signature: "export-csv <INPUT> <OUTPUT>",
+        description: "save the model <INPUT> as CSV file <OUTPUT> with a `context,token,freq` row per continuation, e.g. for spreadsheets",
+        flags: &[],
+    },
+    Subcommand {
+        name: "import-csv",
+        signature: "import-csv <INPUT> <OUTPUT>",
+        description: "convert CSV file <INPUT> in the format of export-csv to binary model file <OUTPUT>. Repeated rows add up",
+        flags: &[
+            ("--order <N>", "order of the contexts in <INPUT>, which CSV has no place for (default: 8)"),
+        ],
+    },
+    Subcommand {
+        name: "convert-legacy",
+        signature: "convert-legacy <INPUT> <OUTPUT>",
+        description: "convert model file <INPUT> written by the original carrotson, which has no header, to the current format <OUTPUT>. The rest of the subcommands read such files as they are, this only saves them the guessing",
+        flags: &[],
+    },
+    Subcommand {
+        name: "dump",
+        signature: "dump <FILE>",
+        description: "print every context of the model <FILE> with its most frequent continuations, the most observed contexts first",
+        flags: &[
+            ("--max-tokens <N>", "print at most <N> continuations per context (default: 8)"),
+        ],
+    },
+    Subcommand {
+        name: "dot",
+        signature: "dot <FILE>",
+        description: "print the model <FILE> as a Graphviz digraph of the contexts made of printable ASCII, e.g. for `dot -Tpng`",
+        flags: &[
+            ("--min-count <K>", "leave out the continuations seen less than <K> times (default: 1)"),
+            ("--max-nodes <N>", "include only <N> contexts seen the most times (default: 100)"),
+        ],
+    },
+    Subcommand {
+        name: "info",
+        signature: "info <FILE>",
+        description: "print the order, the training mode, the creation time and the sources of the model <FILE> without loading the whole model",
+        flags: &[],
+    },
+    Subcommand {
+        name: "stats",
+        signature: "stats <FILE>",
+        description: "print some stats of the model that is trained from <FILE> (`-` for stdin)",
+        flags: &[
+            ("--json", "print the stats as a JSON object instead"),
+            ("--per-line", "train as in `train --per-line`"),
+            ("--top <N>", "also list <N> contexts with the most observations"),
+        ],
+    },
+];
+
+const GLOBAL_FLAGS: &[(&str, &str)] = &[
+    ("--deterministic", "use a fixed seed instead of the clock so every run is reproducible, and record no creation time into the new models, which otherwise honor SOURCE_DATE_EPOCH. Intended for tests and demos, not for production variety"),
+];
+
+// Seed used by --deterministic
+const DETERMINISTIC_SEED: u64 = 69;
+
+// Requests `serve` handles at the same time, the rest are turned away until some finish
+const SERVE_MAX_HANDLERS: usize = 64;
+// How long `serve` waits for a client to send the request or take the response
+const SERVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Generates a text with a model of any kind, handing it over piece by piece to the
+// callback, and returns the first generated byte like `generate_streaming`. `Sync`, since
+// the handlers of `serve` share it.
+type SampleGenerator = Box<dyn Fn(&GenConfig, &mut LCG, &mut dyn FnMut(&[u8])) -> Option<u8> + Sync>;
+
+fn usage(program: &str) {
+    eprintln!("Usage: {program} [GLOBAL FLAGS] <SUBCOMMANDS> [OPTIONS]");
+    eprintln!("Flags may be placed anywhere after the subcommand, the global ones before it as well.");
+    eprintln!("Global flags:");
+    for (flag, description) in GLOBAL_FLAGS.iter() {
+        eprintln!("    {flag:<26} {description}");
+    }
+    eprintln!("Subcommands:");
+    for subcommand in SUBCOMMANDS.iter() {
+        eprintln!("    {:<26} {}", subcommand.signature, subcommand.description);
     }
+    eprintln!("Run `{program} <SUBCOMMAND> --help` to see the options of a particular subcommand.");
+}
 
-    fn random_u32(&mut self) -> u32 {
-        // Stolen from https://en.wikipedia.org/wiki/Linear_congruential_generator
-        // Using the values of MMIX by Donald Knuth
-        const RAND_A: u64 = 6364136223846793005;
-        const RAND_C: u64 = 1442695040888963407;
-        (self.state, _) = self.state.overflowing_mul(RAND_A);
-        (self.state, _) = self.state.overflowing_add(RAND_C);
-        return (self.state>>32) as u32;
+fn subcommand_usage(program: &str, name: &str) {
+    let subcommand = SUBCOMMANDS.iter().find(|subcommand| subcommand.name == name).expect("Subcommand should be always present in SUBCOMMANDS");
+    eprintln!("Usage: {program} {} [OPTIONS]", subcommand.signature);
+    eprintln!("    {}", subcommand.description);
+    eprintln!("Options:");
+    for (flag, description) in subcommand.flags.iter() {
+        eprintln!("    {flag:<26} {description}");
     }
+    eprintln!("    {:<26} print this help and exit", "-h, --help");
 }
 
-#[derive(Debug)]
-struct Freq {
-    tokens: Vec<(u8, u32)>,
+fn flag_value(program: &str, subcommand: &str, flag: &str, args: &mut impl Iterator<Item = String>) -> String {
+    args.next().unwrap_or_else(|| {
+        subcommand_usage(program, subcommand);
+        eprintln!("ERROR: no value is provided for flag {flag}");
+        exit(1)
+    })
 }
 
-fn read_u8(r: &mut impl io::Read) -> io::Result<u8> {
-    let mut buf = [0; 1];
-    r.read_exact(&mut buf)?;
-    Ok(u8::from_le_bytes(buf))
+fn parse_flag_value<T: FromStr>(program: &str, subcommand: &str, flag: &str, args: &mut impl Iterator<Item = String>) -> T {
+    let text = flag_value(program, subcommand, flag, args);
+    text.parse::<T>().unwrap_or_else(|_| {
+        eprintln!("ERROR: `{text}` is not a valid value for flag {flag}");
+        exit(1)
+    })
 }
 
-fn read_u32(r: &mut impl io::Read) -> io::Result<u32> {
-    let mut buf = [0; 4];
-    r.read_exact(&mut buf)?;
-    Ok(u32::from_le_bytes(buf))
+fn push_positional(program: &str, subcommand: &str, arg: String, positional: &mut Vec<String>) {
+    if arg.starts_with('-') && arg.len() > 1 {
+        subcommand_usage(program, subcommand);
+        eprintln!("ERROR: unknown flag `{arg}`");
+        exit(1);
+    }
+    positional.push(arg);
 }
 
-fn read_u64(r: &mut impl io::Read) -> io::Result<u64> {
-    let mut buf = [0; 8];
-    r.read_exact(&mut buf)?;
-    Ok(u64::from_le_bytes(buf))
+fn expect_no_more_positional(program: &str, subcommand: &str, mut positional: impl Iterator<Item = String>) {
+    if let Some(arg) = positional.next() {
+        subcommand_usage(program, subcommand);
+        eprintln!("ERROR: unexpected argument `{arg}`");
+        exit(1);
+    }
 }
 
-impl Freq {
-    fn branching(&self) -> usize {
-        return self.tokens.len();
+// Opens the input file for reading it piece by piece, `-` meaning stdin. Also tells its
+// size if it's known upfront.
+fn open_input(file_path: &str) -> io::Result<(Box<dyn Read>, Option<u64>)> {
+    if file_path == "-" {
+        Ok((Box::new(io::stdin().lock()), None))
+    } else {
+        let file = fs::File::open(file_path)?;
+        let size = file.metadata()?.len();
+        Ok((Box::new(file), Some(size)))
     }
+}
 
-    fn new() -> Self {
-        Self { tokens: Vec::new() }
+// Reads the whole input file, `-` meaning stdin
+fn read_input(file_path: &str) -> io::Result<Vec<u8>> {
+    if file_path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().lock().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        fs::read(file_path)
     }
+}
 
-    fn push(&mut self, x: u8) {
-        let mut found = false;
-        for (y, p) in self.tokens.iter_mut() {
-            if *y == x {
-                *p += 1;
-                found = true;
-                break;
-            }
-        }
+// Seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(
+        |d| d.as_secs()
+    ).unwrap_or_else(
+        |e| e.duration().as_secs()
+    )
+}
 
-        if !found {
-            self.tokens.push((x, 1))
-        }
+// Creation time to record into a new model: none with --deterministic, so the same input
+// gives the same bytes, otherwise SOURCE_DATE_EPOCH if it's set, for the reproducible builds
+fn creation_time(deterministic: bool) -> u64 {
+    if deterministic {
+        return 0
     }
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(text) => text.trim().parse().unwrap_or_else(|_| {
+            eprintln!("ERROR: SOURCE_DATE_EPOCH must be a number of seconds, but got `{text}`");
+            exit(1)
+        }),
+        Err(_) => unix_time(),
+    }
+}
+
+// `unix_time` as `YYYY-MM-DD HH:MM:SS UTC`
+fn time_text(time: u64) -> String {
+    let (days, seconds) = (time/86400, time%86400);
+    // Howard Hinnant's days_from_civil in reverse, with the eras starting at 0000-03-01
+    let days = days + 719468;
+    let era = days/146097;
+    let day_of_era = days%146097;
+    let year_of_era = (day_of_era - day_of_era/1460 + day_of_era/36524 - day_of_era/146096)/365;
+    let day_of_year = day_of_era - (365*year_of_era + year_of_era/4 - year_of_era/100);
+    let mp = (5*day_of_year + 2)/153;
+    let day = day_of_year - (153*mp + 2)/5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era*400 + year_of_era + (month <= 2) as u64;
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC", seconds/3600, seconds/60%60, seconds%60)
+}
+
+fn mode_text(metadata: &Metadata) -> String {
+    let names = metadata.mode_names();
+    if names.is_empty() { "default".to_string() } else { names.join(", ") }
+}
+
+fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+fn read_model_file<T>(file_path: &str, read: impl FnOnce(&mut io::BufReader<fs::File>) -> io::Result<T>) -> T {
+    let file = fs::File::open(file_path).unwrap_or_else(|err| {
+        eprintln!("ERROR: could not read from file {file_path}: {err}");
+        exit(1);
+    });
+    read(&mut io::BufReader::with_capacity(200*1024*1024, file)).unwrap_or_else(|err| {
+        eprintln!("ERROR: could not read from file {file_path}: {err}");
+        exit(1);
+    })
+}
+
+fn load_model(file_path: &str) -> Model {
+    read_model_file(file_path, Model::read_from)
+}
 
-    fn random(&self, lcg: &mut LCG) -> Option<u8> {
-        let sum: usize = self.tokens.iter().map(|(_, p)| *p as usize).sum();
+fn load_word_model(file_path: &str) -> WordModel {
+    read_model_file(file_path, WordModel::read_from)
+}
+
+fn load_codepoint_model(file_path: &str) -> CodepointModel {
+    read_model_file(file_path, CodepointModel::read_from)
+}
+
+fn model_info(file_path: &str) -> ModelInfo {
+    let result = fs::File::open(file_path).and_then(|file| read_model_info(&mut io::BufReader::new(file)));
+    result.unwrap_or_else(|err| {
+        eprintln!("ERROR: could not read from file {file_path}: {err}");
+        exit(1);
+    })
+}
+
+fn write_model_file(file_path: &str, write: impl FnOnce(&mut io::BufWriter<fs::File>) -> io::Result<()>) {
+    let file = fs::File::create(file_path).unwrap_or_else(|err| {
+        eprintln!("ERROR: could not write file {file_path}: {err}");
+        exit(1)
+    });
+    write(&mut io::BufWriter::new(file)).unwrap_or_else(|err| {
+        eprintln!("ERROR: could not write file {file_path}: {err}");
+        exit(1)
+    });
+}
+
+fn save_model(file_path: &str, model: &Model) {
+    write_model_file(file_path, |w| model.write_to(w))
+}
+
+fn save_word_model(file_path: &str, model: &WordModel) {
+    write_model_file(file_path, |w| model.write_to(w))
+}
 
-        if sum > 0 {
-            let index = (lcg.random_u32() as usize)%sum;
-            let mut psum: usize = 0;
-            for (y, p) in self.tokens.iter() {
-                psum += *p as usize;
-                if psum > index {
-                    return Some(*y)
+fn save_codepoint_model(file_path: &str, model: &CodepointModel) {
+    write_model_file(file_path, |w| model.write_to(w))
+}
+
+// Writes as much of `pending` as possible replacing invalid UTF-8 the same way
+// String::from_utf8_lossy does. An incomplete UTF-8 sequence at the end stays in `pending`
+// waiting for the rest of it, unless it's the `last` call.
+fn write_lossy(w: &mut impl Write, pending: &mut Vec<u8>, last: bool) -> io::Result<()> {
+    let mut rest = &pending[..];
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(text) => {
+                w.write_all(text.as_bytes())?;
+                rest = &[];
+                break
+            }
+            Err(err) => {
+                w.write_all(&rest[..err.valid_up_to()])?;
+                match err.error_len() {
+                    Some(len) => {
+                        w.write_all(char::REPLACEMENT_CHARACTER.encode_utf8(&mut [0; 4]).as_bytes())?;
+                        rest = &rest[err.valid_up_to() + len..];
+                    }
+                    None if last => {
+                        w.write_all(char::REPLACEMENT_CHARACTER.encode_utf8(&mut [0; 4]).as_bytes())?;
+                        rest = &[];
+                        break
+                    }
+                    None => {
+                        rest = &rest[err.valid_up_to()..];
+                        break
+                    }
                 }
             }
         }
-        None
     }
+    let done = pending.len() - rest.len();
+    pending.drain(..done);
+    Ok(())
+}
 
-    fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
-        w.write_all(&(self.tokens.len() as u8).to_le_bytes())?;
-        for (x, p) in self.tokens.iter() {
-            w.write_all(&x.to_le_bytes())?;
-            w.write_all(&p.to_le_bytes())?;
-        }
-        Ok(())
+// What followed `prefix` (or its longest known suffix, or the beginning of the text if it is empty)
+fn prefix_freq(model: &Model, prefix: &[u8]) -> Freq {
+    if prefix.is_empty() {
+        return model.model.get(&model.start_context()).cloned().unwrap_or_else(Freq::new)
     }
-
-    fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
-        let mut result = Self::new();
-        let count = read_u8(r)?;
-        for _ in 0..count {
-            let x = read_u8(r)?;
-            let p = read_u32(r)?;
-            result.tokens.push((x, p));
+    let mut context = 0;
+    for x in prefix.iter().copied() {
+        context_push(&mut context, x, model.order);
+    }
+    let len = std::cmp::min(prefix.len(), model.order as usize) as u8;
+    if len == model.order {
+        if let Some(freq) = model.model.get(&context).filter(|freq| !freq.tokens.is_empty()) {
+            return freq.clone()
         }
-        Ok(result)
     }
+    let len = std::cmp::min(len, model.order - 1);
+    (1..=len).rev().map(|len| model.suffix_freq(context, len)).find(|freq| !freq.tokens.is_empty()).unwrap_or_else(Freq::new)
 }
 
-#[derive(Debug)]
-struct Model {
-    model: HashMap<u64, Freq>,
+// How the text that `preprocessing` was done to is called in the messages
+fn preprocessing_text(preprocessing: Preprocessing) -> String {
+    let names = preprocessing.names();
+    if names.is_empty() { "unprocessed".to_string() } else { names.join(", ") }
 }
 
-impl Model {
-    fn new() -> Self {
-        Self {
-            model: HashMap::new()
+// Bytes of the context without the leading zero bytes, which are the padding of the
+// contexts at the very beginning of the input
+fn context_text(context: u64, order: u8) -> Vec<u8> {
+    let mut bytes = context_bytes(context, order);
+    bytes.drain(..bytes.iter().position(|x| *x != 0).unwrap_or(bytes.len()));
+    bytes
+}
+
+// Renders the bytes as the body of a JSON string
+fn json_escape_bytes(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for x in bytes.iter() {
+        match x {
+            b'"' => result.push_str("\\\""),
+            b'\\' => result.push_str("\\\\"),
+            0x20..=0x7E => result.push(*x as char),
+            _ => result.push_str(&format!("\\u{:04x}", x)),
         }
     }
+    result
+}
 
-    fn random(&self, context: u64, lcg: &mut LCG) -> Option<u8> {
-        self.model.get(&context).and_then(|freq| freq.random(lcg))
+// Renders the bytes as the body of a DOT string
+fn dot_escape_bytes(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for x in bytes.iter() {
+        match x {
+            b'"' => result.push_str("\\\""),
+            b'\\' => result.push_str("\\\\"),
+            _ => result.push(*x as char),
+        }
     }
+    result
+}
 
-    fn push(&mut self, context: u64, next: u8) {
-        match self.model.get_mut(&context) {
-            Some(freq) => freq.push(next),
-            None => {
-                let mut freq = Freq::new();
-                freq.push(next);
-                self.model.insert(context, freq);
+fn write_dot(model: &Model, min_count: u32, max_nodes: usize, w: &mut impl Write) -> io::Result<()> {
+    // Only the contexts that can be labeled readably, seen the most times first
+    let mut nodes: Vec<(u64, &Freq)> = model.sorted().into_iter().filter(|(context, _)| {
+        let text = context_text(*context, model.order);
+        !text.is_empty() && text.iter().all(|x| (0x20..=0x7E).contains(x))
+    }).collect();
+    nodes.sort_by_key(|(_, freq)| std::cmp::Reverse(freq.tokens.iter().map(|(_, p)| *p as u64).sum::<u64>()));
+    nodes.truncate(max_nodes);
+    nodes.sort_by_key(|(context, _)| *context);
+
+    writeln!(w, "digraph carrotson {{")?;
+    for (context, _) in nodes.iter() {
+        writeln!(w, "    n{context} [label=\"{}\"];", dot_escape_bytes(&context_text(*context, model.order)))?;
+    }
+    for (context, freq) in nodes.iter() {
+        for (x, p) in freq.tokens.iter().filter(|(_, p)| *p >= min_count) {
+            let mut next = *context;
+            context_push(&mut next, *x, model.order);
+            if nodes.binary_search_by_key(&next, |(context, _)| *context).is_ok() {
+                writeln!(w, "    n{context} -> n{next} [label=\"'{}' ({p})\", weight={p}];", dot_escape_bytes(&escape_bytes(&[*x]).into_bytes()))?;
             }
         }
     }
+    writeln!(w, "}}")?;
+    w.flush()
+}
 
-    fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
-        w.write_all(&(self.model.len() as u64).to_le_bytes())?;
-        for (context, freq) in self.model.iter() {
-            w.write_all(&context.to_le_bytes())?;
-            freq.write_to(w)?;
+fn write_json(model: &Model, w: &mut impl Write) -> io::Result<()> {
+    writeln!(w, "{{")?;
+    writeln!(w, "  \"order\": {},", model.order)?;
+    writeln!(w, "  \"contexts\": [")?;
+    for (i, (context, freq)) in model.sorted().into_iter().enumerate() {
+        let text = context_text(context, model.order);
+        write!(w, "    {{\"context\": {context}, \"text\": \"{}\", \"tokens\": [", json_escape_bytes(&text))?;
+        for (j, (x, p)) in freq.tokens.iter().enumerate() {
+            if j > 0 {
+                write!(w, ", ")?;
+            }
+            write!(w, "{{\"token\": {x}, \"freq\": {p}}}")?;
         }
-        w.flush()?;
-        Ok(())
+        writeln!(w, "]}}{}", if i + 1 < model.model.len() { "," } else { "" })?;
     }
+    writeln!(w, "  ]")?;
+    writeln!(w, "}}")?;
+    w.flush()
+}
 
-    fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
-        let mut result = Self::new();
-        let count = read_u64(r)?;
-        result.model.reserve(count as usize);
-        for _ in 0..count {
-            let context = read_u64(r)?;
-            let freq = Freq::read_from(r)?;
-            result.model.insert(context, freq);
+fn write_dump(model: &Model, max_tokens: usize, w: &mut impl Write) -> io::Result<()> {
+    let mut contexts: Vec<(u64, u64, &Freq)> = model.sorted().into_iter()
+        .map(|(context, freq)| (context, freq.tokens.iter().map(|(_, p)| *p as u64).sum::<u64>(), freq))
+        .collect();
+    // Stable, so the contexts seen equally often stay ordered by the context
+    contexts.sort_by_key(|(_, total, _)| std::cmp::Reverse(*total));
+    for (context, total, freq) in contexts {
+        let text = format!("\"{}\"", escape_bytes(&context_text(context, model.order)));
+        write!(w, "{text:<24} {total:>8} |")?;
+        let tokens = freq.most_frequent(max_tokens).tokens;
+        for (i, (x, p)) in tokens.iter().enumerate() {
+            write!(w, "{} '{}' {p}", if i > 0 { "," } else { "" }, escape_bytes(&[*x]))?;
         }
-        Ok(result)
+        if freq.tokens.len() > tokens.len() {
+            write!(w, ", ... {} more", freq.tokens.len() - tokens.len())?;
+        }
+        writeln!(w)?;
     }
+    w.flush()
 }
 
-struct Slicer {
-    bytes: Vec<u8>,
-    window: u64,
-    cursor: usize,
-}
-
-impl Slicer {
-    fn new(bytes: Vec<u8>) -> Self {
-        Self{bytes, window: 0, cursor: 0}
+fn write_csv(model: &Model, w: &mut impl Write) -> io::Result<()> {
+    writeln!(w, "context,token,freq")?;
+    for (context, freq) in model.sorted() {
+        for (x, p) in freq.tokens.iter() {
+            writeln!(w, "{context},{x},{p}")?;
+        }
     }
+    w.flush()
 }
 
-impl Iterator for Slicer {
-    type Item = (u64, u8);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.cursor >= self.bytes.len() {
-            return None
+fn model_from_csv(bytes: &[u8], order: u8) -> Result<Model, String> {
+    let mut model = Model::new(order);
+    let text = std::str::from_utf8(bytes).map_err(|_| "invalid UTF-8".to_string())?;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line == "context,token,freq") {
+            continue
         }
-
-        let result = self.window;
-        let next = self.bytes[self.cursor];
-        self.window = (self.window<<8)|(next as u64);
-        self.cursor += 1;
-
-        return Some((result, next));
+        let row = i + 1;
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [context, x, p] = fields[..] else {
+            return Err(format!("line {row}: expected 3 fields `context,token,freq`, but got {}", fields.len()));
+        };
+        let context: u64 = context.parse().map_err(|_| format!("line {row}: context must be an unsigned 64 bit integer, but got `{context}`"))?;
+        if context & context_mask(order) != context {
+            return Err(format!("line {row}: context {context} does not fit into order {order}"));
+        }
+        let x: u8 = x.parse().map_err(|_| format!("line {row}: token must be within 0..=255, but got `{x}`"))?;
+        let p: u32 = p.parse().map_err(|_| format!("line {row}: freq must be an integer that fits into 32 bits, but got `{p}`"))?;
+        model.push_n(context, x, p);
     }
+    Ok(model)
 }
 
-fn context_push(context: &mut u64, x: u8) {
-    *context = ((*context)<<8)|(x as u64);
-}
-
-fn usage(program: &str) {
-    eprintln!("Usage: {program} <SUBCOMMANDS> [OPTIONS]");
-    eprintln!("Subcommands:");
-    eprintln!("    train <INPUT> <OUTPUT>     generate binary model file <OUTPUT> based on <INPUT>");
-    eprintln!("    gen <FILE> [-l <LIMIT>]    generate random text based on a model trained from <FILE>");
-    eprintln!("    stats <FILE>               print some stats of the model that is trained from <FILE>");
+fn model_from_json(json: &Json) -> Result<Model, String> {
+    let order = json.get("order").and_then(Json::as_u64).ok_or("`order` must be an integer")?;
+    if !(1..=MAX_ORDER as u64).contains(&order) {
+        return Err(format!("order must be within 1..={MAX_ORDER}, but got {order}"));
+    }
+    let mut model = Model::new(order as u8);
+    let contexts = json.get("contexts").and_then(Json::as_array).ok_or("`contexts` must be an array")?;
+    for (i, entry) in contexts.iter().enumerate() {
+        let context = entry.get("context").and_then(Json::as_u64).ok_or(format!("context #{i}: `context` must be an unsigned 64 bit integer"))?;
+        if context & context_mask(model.order) != context {
+            return Err(format!("context #{i}: context {context} does not fit into order {order}"));
+        }
+        let tokens = entry.get("tokens").and_then(Json::as_array).ok_or(format!("context #{i}: `tokens` must be an array"))?;
+        let mut freq = Freq::new();
+        for token in tokens.iter() {
+            let x = token.get("token").and_then(Json::as_u64).ok_or(format!("context #{i}: `token` must be an integer"))?;
+            let x = u8::try_from(x).map_err(|_| format!("context #{i}: token must be within 0..=255, but got {x}"))?;
+            let p = token.get("freq").and_then(Json::as_u64).ok_or(format!("context #{i}: `freq` must be an integer"))?;
+            let p = u32::try_from(p).map_err(|_| format!("context #{i}: freq {p} does not fit into 32 bits"))?;
+            if freq.tokens.iter().any(|(y, _)| *y == x) {
+                return Err(format!("context #{i}: token {x} is listed more than once"));
+            }
+            freq.tokens.push((x, p));
+        }
+        if model.model.insert(context, freq).is_some() {
+            return Err(format!("context #{i}: context {context} is listed more than once"));
+        }
+    }
+    Ok(model)
 }
 
 fn main() {
-    let mut lcg = LCG::new(
-        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(
-            |d| d.as_secs()
-        ).unwrap_or_else(
-            |e| e.duration().as_secs()
-        )
-    );
-
     let mut args = env::args();
     let program = args.next().expect("Program name should be always present");
 
-    let subcommand = args.next().unwrap_or_else(|| {
-        usage(&program);
-        eprintln!("ERROR: no subcommand is provided");
-        exit(1);
-    });
+    let mut deterministic = false;
+    let subcommand = loop {
+        let arg = args.next().unwrap_or_else(|| {
+            usage(&program);
+            eprintln!("ERROR: no subcommand is provided");
+            exit(1);
+        });
+        match arg.as_str() {
+            // Also accepted by every subcommand, which ignores it unless it has something to randomize
+            "--deterministic" => deterministic = true,
+            _ => break arg,
+        }
+    };
 
     match subcommand.as_str() {
-        "gen" => {
-            let file_path = args.next().unwrap_or_else(|| {
-                usage(&program);
+        "gen" | "repl" | "serve" => {
+            let name = subcommand.as_str();
+            let repl = name == "repl";
+            let serve = name == "serve";
+            let mut host = String::from("127.0.0.1");
+            let mut port: u16 = 8080;
+            let mut positional = Vec::new();
+            let mut limit = None;
+            let mut order: Option<u8> = None;
+            let mut word_limit: Option<usize> = None;
+            let mut seed = None;
+            let mut count = 1;
+            let mut quiet = false;
+            let mut stream = false;
+            let mut output_file_path = None;
+            let mut separator = "\n".to_string();
+            let mut diverse_starts = false;
+            let mut config = GenConfig {
+                limit: 1024,
+                prompt: String::new(),
+                flatten_counts: false,
+                utf8_boundary: false,
+                temperature: 1.0,
+                top_k: None,
+                top_p: None,
+                stop: Vec::new(),
+                trim_stop: false,
+                min_length: 0,
+                eos: false,
+                backoff: true,
+                smoothing: 0.0,
+                random_start: false,
+                greedy: false,
+                beam: None,
+                repetition_penalty: 1.0,
+                cycle_guard: true,
+                word_limit: 0,
+                used_starts: Vec::new(),
+            };
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => deterministic = true,
+                    "--no-backoff" => config.backoff = false,
+                    "--no-cycle-guard" => config.cycle_guard = false,
+                    "--utf8-boundary" => config.utf8_boundary = true,
+                    "--eos" => config.eos = true,
+                    "-q" | "--quiet" => quiet = true,
+                    "--stream" => stream = true,
+                    "-o" | "--output" => output_file_path = Some(flag_value(&program, name, &arg, &mut args)),
+                    "--host" if serve => host = flag_value(&program, name, &arg, &mut args),
+                    "--port" if serve => port = parse_flag_value(&program, name, &arg, &mut args),
+                    "--infinite" => limit = Some(0),
+                    "-l" | "--limit" => limit = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--words" => word_limit = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--seed" => seed = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--order" => order = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--count" => count = parse_flag_value(&program, name, &arg, &mut args),
+                    "--separator" => separator = unescape(&flag_value(&program, name, &arg, &mut args)),
+                    "--diverse-starts" => diverse_starts = true,
+                    "--random-start" => config.random_start = true,
+                    "--prompt" => config.prompt = flag_value(&program, name, &arg, &mut args),
+                    "--flatten-counts" => config.flatten_counts = true,
+                    "--greedy" => config.greedy = true,
+                    "--beam" => config.beam = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--top-k" => config.top_k = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--top-p" => config.top_p = Some(parse_flag_value(&program, name, &arg, &mut args)),
+                    "--stop" => config.stop = unescape(&flag_value(&program, name, &arg, &mut args)).into_bytes(),
+                    "--trim-stop" => config.trim_stop = true,
+                    "--min-length" => config.min_length = parse_flag_value(&program, name, &arg, &mut args),
+                    "--smoothing" => config.smoothing = parse_flag_value(&program, name, &arg, &mut args),
+                    "--repetition-penalty" => config.repetition_penalty = parse_flag_value(&program, name, &arg, &mut args),
+                    "--temperature" => config.temperature = parse_flag_value(&program, name, &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, name);
+                        exit(0);
+                    }
+                    _ => push_positional(&program, name, arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, name);
                 eprintln!("ERROR: no input file is provided");
                 exit(1);
             });
 
-            let limit = args.next().map(|text| {
+            // The limit used to be a bare positional argument. Keep accepting it that way.
+            config.limit = limit.or_else(|| positional.next().map(|text| {
                 text.parse::<usize>().unwrap_or_else(|_| {
                     eprintln!("ERROR: limit must be an integer. Sadly `{text}` does not look like an integer.");
                     exit(1)
                 })
-            }).unwrap_or(1024);
+            })).unwrap_or_else(|| match word_limit {
+                // Just a safety net for the models that never generate any whitespace
+                Some(words) => words.saturating_mul(64),
+                None => config.limit,
+            });
+            config.word_limit = word_limit.unwrap_or(0);
+            let seed = seed.unwrap_or_else(|| if deterministic { DETERMINISTIC_SEED } else { unix_time() });
+            expect_no_more_positional(&program, name, positional);
 
-            println!("Loading the model from {file_path}...");
-            let file = fs::File::open(&file_path).unwrap_or_else(|err| {
-                eprintln!("ERROR: could not read from file {file_path}: {err}");
+            if config.random_start && !config.prompt.is_empty() {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --random-start and --prompt can't be used together");
                 exit(1);
-            });
-            let model = Model::read_from(&mut io::BufReader::with_capacity(200*1024*1024, file)).unwrap_or_else(|err| {
-                eprintln!("ERROR: could not read from file {file_path}: {err}");
+            }
+            if serve && output_file_path.is_some() {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: serve sends the text to the clients, --output makes no sense there");
+                exit(1);
+            }
+            if (repl || serve) && config.limit == 0 {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: every prompt of {name} needs a limit to stop at");
+                exit(1);
+            }
+            if config.limit == 0 && config.beam.is_some() {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --beam needs a limit to search within");
                 exit(1);
+            }
+            if word_limit == Some(0) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: word limit must be at least 1");
+                exit(1);
+            }
+            if config.beam == Some(0) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: beam width must be at least 1");
+                exit(1);
+            }
+            if config.top_k == Some(0) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: top-k must be at least 1");
+                exit(1);
+            }
+            if config.top_k.is_some() && config.top_p.is_some() {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --top-k and --top-p can't be used together");
+                exit(1);
+            }
+            if let Some(p) = config.top_p {
+                if !(0.0..=1.0).contains(&p) {
+                    subcommand_usage(&program, name);
+                    eprintln!("ERROR: top-p must be within 0.0..=1.0, but got {p}");
+                    exit(1);
+                }
+            }
+            if config.temperature.is_nan() || config.temperature < 0.0 {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: temperature must be a non-negative number, but got {}", config.temperature);
+                exit(1);
+            }
+            if config.smoothing.is_nan() || config.smoothing < 0.0 {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: smoothing must be a non-negative number, but got {}", config.smoothing);
+                exit(1);
+            }
+            if config.smoothing > 0.0 && (config.flatten_counts || config.temperature != 1.0 || config.utf8_boundary) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --smoothing can't be combined with --flatten-counts, --temperature or --utf8-boundary");
+                exit(1);
+            }
+            if config.greedy && (config.flatten_counts || config.temperature != 1.0 || config.smoothing > 0.0) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --greedy can't be combined with --flatten-counts, --temperature or --smoothing");
+                exit(1);
+            }
+            if config.repetition_penalty.is_nan() || config.repetition_penalty <= 0.0 {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: repetition penalty must be a positive number, but got {}", config.repetition_penalty);
+                exit(1);
+            }
+            if config.repetition_penalty != 1.0 && (config.flatten_counts || config.smoothing > 0.0 || config.greedy) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --repetition-penalty can't be combined with --flatten-counts, --smoothing or --greedy");
+                exit(1);
+            }
+            if diverse_starts && (config.beam.is_some() || config.flatten_counts || config.smoothing > 0.0) {
+                subcommand_usage(&program, name);
+                eprintln!("ERROR: --diverse-starts can't be combined with --beam, --flatten-counts or --smoothing");
+                exit(1);
+            }
+
+            let info = model_info(&file_path);
+            let kind = info.kind;
+            // The contexts are always masked to the order of the model, the flag is only there
+            // to catch the scripts that expect some other model
+            if let Some(order) = order {
+                if order != info.order {
+                    eprintln!("ERROR: {file_path} has order {}, but order {order} was requested", info.order);
+                    exit(1);
+                }
+            }
+            if kind != ModelKind::Bytes {
+                let byte_only = [
+                    // Codepoints can't break UTF-8 anyway
+                    ("--utf8-boundary", config.utf8_boundary && kind == ModelKind::Words),
+                    ("--smoothing", config.smoothing > 0.0),
+                    ("--beam", config.beam.is_some()),
+                    ("--stop", !config.stop.is_empty()),
+                    ("--eos", config.eos),
+                    ("--min-length", config.min_length > 0),
+                    ("--random-start", config.random_start),
+                    ("--infinite", config.limit == 0),
+                    ("--no-cycle-guard", !config.cycle_guard),
+                    ("--words", config.word_limit > 0),
+                    ("--diverse-starts", diverse_starts),
+                ];
+                if let Some((flag, _)) = byte_only.iter().find(|(_, used)| *used) {
+                    eprintln!("ERROR: {flag} is not supported by {} models like {file_path}", kind.name());
+                    exit(1);
+                }
+            }
+
+            if !quiet {
+                eprintln!("Loading the model from {file_path}...");
+            }
+            // There is no end to wait for
+            let stream = stream || config.limit == 0;
+            // The model only knows the text the way it looked after the preprocessing
+            let preprocess = |text: &[u8], preprocessing: Preprocessing| preprocessing.apply(text.to_vec(), kind);
+            // Hands the generated text over piece by piece to the callback
+            let (preprocessing, generate_sample): (Preprocessing, SampleGenerator) = match kind {
+                ModelKind::Bytes => {
+                    let model = load_model(&file_path);
+                    let index = SuffixIndex::default();
+                    (model.preprocessing, Box::new(move |config, lcg, emit| generate_streaming(&model, &index, config, lcg, emit)))
+                }
+                ModelKind::Words => {
+                    let model = load_word_model(&file_path);
+                    (model.preprocessing, Box::new(move |config, lcg, emit| {
+                        emit(&generate_words(&model, config, lcg));
+                        None
+                    }))
+                }
+                ModelKind::Codepoints => {
+                    let model = load_codepoint_model(&file_path);
+                    (model.preprocessing, Box::new(move |config, lcg, emit| {
+                        emit(&generate_codepoints(&model, config, lcg));
+                        None
+                    }))
+                }
+            };
+            config.prompt = String::from_utf8_lossy(&preprocess(config.prompt.as_bytes(), preprocessing)).into_owned();
+            config.stop = preprocess(&config.stop, preprocessing);
+
+            let mut output = output_file_path.as_ref().map(|output_file_path| {
+                let file = fs::File::create(output_file_path).unwrap_or_else(|err| {
+                    eprintln!("ERROR: could not write file {output_file_path}: {err}");
+                    exit(1)
+                });
+                (output_file_path, io::BufWriter::new(file))
             });
+            let print_samples = |config: &GenConfig, lcg: &mut LCG, output: &mut Option<(&String, io::BufWriter<fs::File>)>| {
+                // The starts of --diverse-starts are tracked only within the batch
+                let mut config = config.clone();
+                for i in 0..count {
+                    let end = if i + 1 < count { separator.as_bytes() } else { b"\n" };
+                    let start;
+                    if let Some((output_file_path, file)) = output {
+                        let mut result = Ok(());
+                        start = generate_sample(&config, lcg, &mut |bytes| {
+                            if result.is_ok() {
+                                result = file.write_all(bytes);
+                            }
+                        });
+                        result.and_then(|_| file.write_all(end)).and_then(|_| file.flush()).unwrap_or_else(|err| {
+                            eprintln!("ERROR: could not write file {output_file_path}: {err}");
+                            exit(1)
+                        });
+                    } else if stream {
+                        let mut out = io::BufWriter::new(io::stdout().lock());
+                        let mut pending = Vec::new();
+                        let mut write = |bytes: &[u8], last: bool| {
+                            pending.extend_from_slice(bytes);
+                            write_lossy(&mut out, &mut pending, last).and_then(|_| {
+                                if last || bytes.contains(&b'\n') { out.flush() } else { Ok(()) }
+                            }).unwrap_or_else(|err| {
+                                if err.kind() == io::ErrorKind::BrokenPipe {
+                                    // Whoever reads it had enough, which is how --infinite is supposed to end
+                                    exit(0)
+                                }
+                                eprintln!("ERROR: could not write the generated text: {err}");
+                                exit(1)
+                            });
+                        };
+                        start = generate_sample(&config, lcg, &mut |bytes| write(bytes, false));
+                        write(end, true);
+                    } else {
+                        let mut buffer = Vec::new();
+                        start = generate_sample(&config, lcg, &mut |bytes| buffer.extend_from_slice(bytes));
+                        print!("{}{}", String::from_utf8_lossy(&buffer), String::from_utf8_lossy(end));
+                        // Otherwise the line-buffered stdout keeps the text if it doesn't end with a newline
+                        io::stdout().flush().unwrap_or_else(|err| {
+                            eprintln!("ERROR: could not write the generated text: {err}");
+                            exit(1)
+                        });
+                    }
+                    if diverse_starts {
+                        config.used_starts.extend(start);
+                    }
+                }
+            };
 
-            println!("Generating text...");
-            println!("------------------------------");
-            let mut context = 0;
-            let mut buffer = Vec::new();
-            while let Some(x) = model.random(context, &mut lcg) {
-                if buffer.len() >= limit {
-                    break
+            if !quiet {
+                eprintln!("Seed: {seed}");
+            }
+            let mut lcg = LCG::new(seed);
+            if serve {
+                let listener = TcpListener::bind((host.as_str(), port)).unwrap_or_else(|err| {
+                    eprintln!("ERROR: could not listen on {host}:{port}: {err}");
+                    exit(1)
+                });
+                if !quiet {
+                    eprintln!("Listening on http://{host}:{port}/gen");
+                }
+                // Requests without a seed get a different one each
+                let next_seed = AtomicU64::new(seed);
+                let max_limit = config.limit;
+                let handlers = AtomicUsize::new(0);
+                let handlers = &handlers;
+                let respond = |request: &http::Request| -> (u16, Vec<u8>) {
+                    if request.method != "GET" {
+                        return (405, b"only GET is supported\n".to_vec())
+                    }
+                    if request.path != "/gen" {
+                        return (404, b"only /gen is here\n".to_vec())
+                    }
+                    let mut config = config.clone();
+                    if let Some(prompt) = request.param("prompt") {
+                        config.prompt = String::from_utf8_lossy(&preprocess(prompt.as_bytes(), preprocessing)).into_owned();
+                    }
+                    if let Some(limit) = request.param("limit") {
+                        match limit.parse() {
+                            // A single request shouldn't be able to occupy the server indefinitely
+                            Ok(limit) if limit > 0 => config.limit = std::cmp::min(limit, max_limit),
+                            _ => return (400, format!("limit must be a positive integer, but got `{limit}`\n").into_bytes()),
+                        }
+                    }
+                    let seed = match request.param("seed").map(str::parse) {
+                        Some(Ok(seed)) => seed,
+                        Some(Err(_)) => return (400, b"seed must be an integer\n".to_vec()),
+                        None => next_seed.fetch_add(1, Ordering::Relaxed) + 1,
+                    };
+                    let mut buffer = Vec::new();
+                    generate_sample(&config, &mut LCG::new(seed), &mut |bytes| buffer.extend_from_slice(bytes));
+                    (200, String::from_utf8_lossy(&buffer).into_owned().into_bytes())
+                };
+                let respond = &respond;
+                thread::scope(|scope| {
+                    for stream in listener.incoming() {
+                        let stream = match stream {
+                            Ok(stream) => stream,
+                            Err(err) => {
+                                eprintln!("ERROR: could not accept a connection: {err}");
+                                continue
+                            }
+                        };
+                        // Clients that stall shouldn't hold on to their handlers forever
+                        let timeouts = stream.set_read_timeout(Some(SERVE_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(SERVE_TIMEOUT)));
+                        if let Err(err) = timeouts {
+                            eprintln!("ERROR: could not set up a connection: {err}");
+                            continue
+                        }
+                        if handlers.fetch_add(1, Ordering::Relaxed) >= SERVE_MAX_HANDLERS {
+                            handlers.fetch_sub(1, Ordering::Relaxed);
+                            if let Err(err) = http::write_response(&mut &stream, 503, b"too many requests at once, try again later\n") {
+                                eprintln!("ERROR: could not send the response: {err}");
+                            }
+                            continue
+                        }
+                        scope.spawn(move || {
+                            let (status, body) = match http::read_request(&mut io::BufReader::new(&stream)) {
+                                Ok(request) => respond(&request),
+                                Err(err) => (400, format!("{err}\n").into_bytes()),
+                            };
+                            if let Err(err) = http::write_response(&mut &stream, status, &body) {
+                                eprintln!("ERROR: could not send the response: {err}");
+                            }
+                            handlers.fetch_sub(1, Ordering::Relaxed);
+                        });
+                    }
+                });
+                return
+            }
+            if !repl {
+                if !quiet {
+                    eprintln!("Generating text...");
+                    eprintln!("------------------------------");
+                }
+                print_samples(&config, &mut lcg, &mut output);
+                return
+            }
+
+            let interactive = io::stdin().is_terminal();
+            if interactive && !quiet {
+                eprintln!("Type a prompt to continue it, or /seed <SEED>, /limit <LIMIT>, /quit");
+            }
+            let mut line = String::new();
+            loop {
+                if interactive {
+                    eprint!("> ");
+                }
+                line.clear();
+                match io::stdin().read_line(&mut line) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(err) => {
+                        eprintln!("ERROR: could not read the prompt: {err}");
+                        exit(1)
+                    }
+                }
+                let prompt = line.strip_suffix('\n').unwrap_or(&line);
+                let prompt = prompt.strip_suffix('\r').unwrap_or(prompt);
+                let mut words = prompt.split_whitespace();
+                match words.next() {
+                    Some("/quit") => break,
+                    Some("/seed") => match words.next().map(str::parse::<u64>) {
+                        Some(Ok(seed)) => lcg = LCG::new(seed),
+                        _ => eprintln!("ERROR: /seed expects an integer"),
+                    },
+                    Some("/limit") => match words.next().map(str::parse::<usize>) {
+                        Some(Ok(limit)) if limit > 0 => config.limit = limit,
+                        _ => eprintln!("ERROR: /limit expects a positive integer"),
+                    },
+                    Some(command) if command.starts_with('/') => eprintln!("ERROR: unknown command `{command}`"),
+                    _ => {
+                        config.prompt = String::from_utf8_lossy(&preprocess(prompt.as_bytes(), preprocessing)).into_owned();
+                        print_samples(&config, &mut lcg, &mut output);
+                    }
                 }
-                buffer.push(x);
-                context_push(&mut context, x);
             }
-            println!("{}", std::str::from_utf8(&buffer).unwrap());
         },
+        "query" => {
+            let mut positional = Vec::new();
+            let mut format = String::from("text");
+            let mut top = usize::MAX;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--format" => format = flag_value(&program, "query", &arg, &mut args),
+                    "--top" => top = parse_flag_value(&program, "query", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "query");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "query", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "query");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let prefix = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "query");
+                eprintln!("ERROR: no prefix is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "query", positional);
+
+            if format != "text" && format != "md" {
+                subcommand_usage(&program, "query");
+                eprintln!("ERROR: unknown format `{format}`");
+                exit(1);
+            }
+
+            let model = load_model(&file_path);
+            let prefix = model.preprocessing.apply(prefix.into_bytes(), ModelKind::Bytes);
+            let continuations = prefix_freq(&model, &prefix).top_k(top);
+
+            if format == "text" {
+                for (x, p) in continuations.iter() {
+                    println!("{:<6} {:.4}", format!("'{}'", escape_bytes(&[*x])), p);
+                }
+            } else {
+                println!("| Continuation | Probability |");
+                println!("|--------------|-------------|");
+                for (x, p) in continuations.iter() {
+                    let token = match escape_bytes(&[*x]).as_str() {
+                        "`" => String::from("`` ` ``"),
+                        "|" => String::from("`\\|`"),
+                        token => format!("`{token}`"),
+                    };
+                    println!("| {token} | {:.2}% |", p*100.0);
+                }
+            }
+        }
+        "complete" => {
+            let mut positional = Vec::new();
+            let mut n = 5;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--n" => n = parse_flag_value(&program, "complete", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "complete");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "complete", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "complete");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let prefix = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "complete");
+                eprintln!("ERROR: no prefix is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "complete", positional);
+
+            let model = load_model(&file_path);
+            let prefix = model.preprocessing.apply(prefix.into_bytes(), ModelKind::Bytes);
+            for (x, p) in prefix_freq(&model, &prefix).top_k(n).iter() {
+                println!("{:<6} {:.4}", format!("'{}'", escape_bytes(&[*x])), p);
+            }
+        }
+        "score" => {
+            let mut positional = Vec::new();
+            let mut floor = 0.000001;
+            let mut backoff = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--floor" => floor = parse_flag_value(&program, "score", &arg, &mut args),
+                    "--backoff" => backoff = true,
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "score");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "score", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "score");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let text_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "score");
+                eprintln!("ERROR: no text file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "score", positional);
+
+            if !(floor > 0.0 && floor <= 1.0) {
+                subcommand_usage(&program, "score");
+                eprintln!("ERROR: floor must be within 0.0..=1.0 excluding 0.0, but got {floor}");
+                exit(1);
+            }
+
+            eprintln!("Loading the model from {file_path}...");
+            let model = load_model(&file_path);
+            let bytes = read_input(&text_file_path).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not read file {text_file_path}: {err}");
+                exit(1)
+            });
+            let bytes = model.preprocessing.apply(bytes, ModelKind::Bytes);
+
+            let index = SuffixIndex::default();
+            let mut unseen = 0;
+            let mut nll = 0.0;
+            // The amount of bytes and their negative log-likelihood by the backoff steps they took
+            let mut depths = vec![(0, 0.0); std::cmp::max(model.order, 1) as usize];
+            for (context, next) in Slicer::new(&bytes, model.order) {
+                let p = if backoff {
+                    model.backoff_probability(&index, context, next).map(|(p, steps)| {
+                        let depth = &mut depths[steps as usize];
+                        depth.0 += 1;
+                        depth.1 -= p.log2();
+                        p
+                    })
+                } else {
+                    model.probability(context, next, false)
+                };
+                let p = p.unwrap_or_else(|| {
+                    unseen += 1;
+                    floor
+                });
+                nll -= p.log2();
+            }
+            let avg_nll = if bytes.is_empty() { 0.0 } else { nll/bytes.len() as f64 };
+            println!("Bytes: {}", bytes.len());
+            println!("Unseen bytes: {unseen}");
+            println!("Average negative log-likelihood: {avg_nll:.4} bits per byte");
+            println!("Perplexity: {:.4}", avg_nll.exp2());
+            if backoff {
+                println!();
+                println!("{:<14} {:>10} {:>8} {:>12}", "Backoff steps", "Bytes", "Share", "Perplexity");
+                let share = |n: usize| if bytes.is_empty() { 0.0 } else { n as f64*100.0/bytes.len() as f64 };
+                for (steps, (n, nll)) in depths.iter().enumerate() {
+                    let perplexity = if *n == 0 { String::from("-") } else { format!("{:.4}", (nll/(*n as f64)).exp2()) };
+                    println!("{:<14} {:>10} {:>7.2}% {:>12}", steps, n, share(*n), perplexity);
+                }
+                // Every one of them is predicted with the floor, nothing to average
+                let perplexity = if unseen == 0 { String::from("-") } else { format!("{:.4}", 1.0/floor) };
+                println!("{:<14} {:>10} {:>7.2}% {:>12}", "unseen", unseen, share(unseen), perplexity);
+            }
+        }
+        "info" => {
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "info");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "info", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "info");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "info", positional);
+
+            let info = model_info(&file_path);
+            if info.version == MODEL_VERSION_LEGACY {
+                // Nothing but the whole file tells whether it's a model at all
+                let model = load_model(&file_path);
+                println!("Format: the original carrotson without a header");
+                println!("Order: {}", model.order);
+                println!("Contexts: {}", model.model.len());
+                return
+            }
+            println!("Format version: {}", info.version);
+            println!("Kind: {}", info.kind.name());
+            println!("Order: {}", info.order);
+            println!("Preprocessing: {}", preprocessing_text(info.preprocessing));
+            let Some(metadata) = info.metadata else {
+                println!("Metadata: none, format version {} predates it", info.version);
+                return
+            };
+            println!("Training mode: {}", mode_text(&metadata));
+            println!("Created: {}", if metadata.created == 0 { "unknown".to_string() } else { time_text(metadata.created) });
+            if metadata.sources.is_empty() {
+                println!("Sources: unknown");
+            } else {
+                println!("Sources:");
+                for source in metadata.sources.iter() {
+                    println!("    {source}");
+                }
+            }
+        }
         "stats" => {
-            let file_path = args.next().unwrap_or_else(|| {
-                usage(&program);
+            let mut positional = Vec::new();
+            let mut json = false;
+            let mut per_line = false;
+            let mut top = 0;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--json" => json = true,
+                    "--per-line" => per_line = true,
+                    "--top" => top = parse_flag_value(&program, "stats", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "stats");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "stats", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "stats");
                 eprintln!("ERROR: no input file is provided");
                 exit(1);
             });
+            expect_no_more_positional(&program, "stats", positional);
 
-            println!("Training the model...");
-            let mut model = Model::new();
-            let bytes = fs::read(&file_path).unwrap_or_else(|err| {
+            eprintln!("Training the model...");
+            let mut model = Model::new(MAX_ORDER);
+            let (mut input, _) = open_input(&file_path).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not read file {file_path}: {err}");
+                exit(1)
+            });
+            let config = TrainConfig {
+                threads: default_threads(),
+                per_line,
+                eos: false,
+                weight: 1,
+                max_records: None,
+                skip_warmup: false,
+                bos: false,
+            };
+            model.metadata.add_training(&file_path, &config);
+            model.train_from_reader(&mut input, &config, |_| {}).unwrap_or_else(|err| {
                 eprintln!("ERROR: could not read file {file_path}: {err}");
                 exit(1)
             });
-            for (context, next) in Slicer::new(bytes) {
-                model.push(context, next)
-            }
 
             let mut max_branching = usize::MIN;
             let mut avg_branching = 0f32;
-            for (_context, freq) in model.model.iter() {
+            let mut max_entropy = 0f64;
+            let mut avg_entropy = 0f64;
+            const HISTOGRAM_BUCKETS: [&str; 6] = ["1", "2", "3-4", "5-8", "9-16", "17+"];
+            let mut histogram = [0usize; HISTOGRAM_BUCKETS.len()];
+            let mut total_observations = 0u64;
+            for freq in model.model.values() {
+                total_observations += freq.tokens.iter().map(|(_, p)| *p as u64).sum::<u64>();
                 let branching = freq.branching();
+                histogram[match branching {
+                    0..=1 => 0,
+                    2 => 1,
+                    3..=4 => 2,
+                    5..=8 => 3,
+                    9..=16 => 4,
+                    _ => 5,
+                }] += 1;
                 max_branching = std::cmp::max(max_branching, branching);
                 avg_branching += branching as f32;
+                let entropy = freq.entropy();
+                max_entropy = max_entropy.max(entropy);
+                avg_entropy += entropy;
+            }
+            if !model.model.is_empty() {
+                avg_branching /= model.model.len() as f32;
+                avg_entropy /= model.model.len() as f64;
+            }
+
+            let (memory_useful, memory_allocated) = model.memory_usage();
+
+            // (context, observations, branching) of the `top` contexts with the most observations
+            let mut top_contexts: Vec<(u64, u64, usize)> = model.model.iter().map(|(context, freq)| {
+                (*context, freq.tokens.iter().map(|(_, p)| *p as u64).sum::<u64>(), freq.branching())
+            }).collect();
+            // Ties are broken by the context so the list doesn't depend on the HashMap order
+            let by_observations = |a: &(u64, u64, usize), b: &(u64, u64, usize)| b.1.cmp(&a.1).then(a.0.cmp(&b.0));
+            if top < top_contexts.len() {
+                if top > 0 {
+                    top_contexts.select_nth_unstable_by(top - 1, by_observations);
+                }
+                top_contexts.truncate(top);
             }
-            avg_branching /= model.model.len() as f32;
+            top_contexts.sort_unstable_by(by_observations);
 
+            if json {
+                let histogram_json: Vec<String> = HISTOGRAM_BUCKETS.iter().zip(histogram.iter()).map(|(bucket, count)| format!("\"{bucket}\": {count}")).collect();
+                let mode_json: Vec<String> = model.metadata.mode_names().iter().map(|name| format!("\"{name}\"")).collect();
+                println!("{{");
+                println!("  \"order\": {},", model.order);
+                println!("  \"training_mode\": [{}],", mode_json.join(", "));
+                println!("  \"records\": {},", model.model.len());
+                println!("  \"total_observations\": {total_observations},");
+                println!("  \"estimated_size\": {},", model.serialized_size());
+                println!("  \"memory_useful\": {memory_useful},");
+                println!("  \"memory_allocated\": {memory_allocated},");
+                println!("  \"max_branching\": {max_branching},");
+                println!("  \"avg_branching\": {avg_branching},");
+                println!("  \"max_entropy\": {max_entropy},");
+                println!("  \"avg_entropy\": {avg_entropy},");
+                print!("  \"branching_histogram\": {{{}}}", histogram_json.join(", "));
+                if top > 0 {
+                    let top_json: Vec<String> = top_contexts.iter().map(|(context, observations, branching)| {
+                        format!("{{\"context\": {context}, \"text\": \"{}\", \"observations\": {observations}, \"branching\": {branching}}}", json_escape_bytes(&context_text(*context, model.order)))
+                    }).collect();
+                    print!(",\n  \"top_contexts\": [{}]", top_json.join(", "));
+                }
+                println!();
+                println!("}}");
+                return;
+            }
+
+            println!("Order: {}", model.order);
+            println!("Training mode: {}", mode_text(&model.metadata));
             println!("Records count: {}", model.model.len());
+            println!("Total observations: {total_observations}");
+            println!("Estimated model size: {} bytes", model.serialized_size());
+            println!("Estimated memory usage: {memory_useful} bytes useful, {memory_allocated} bytes allocated");
             println!("Maximum branching: {max_branching}");
             println!("Average branching: {avg_branching}");
+            println!("Maximum entropy: {max_entropy:.4} bits");
+            println!("Average entropy: {avg_entropy:.4} bits");
+            println!("Branching histogram:");
+            const HISTOGRAM_WIDTH: usize = 40;
+            let histogram_max = histogram.iter().copied().max().unwrap_or(0).max(1);
+            for (bucket, count) in HISTOGRAM_BUCKETS.iter().zip(histogram.iter()) {
+                let bar = "#".repeat((count*HISTOGRAM_WIDTH).div_ceil(histogram_max));
+                println!("    {bucket:>5} | {bar:<HISTOGRAM_WIDTH$} {count}");
+            }
+            if top > 0 {
+                println!("Top {} contexts:", top_contexts.len());
+                for (context, observations, branching) in top_contexts.iter() {
+                    let text = format!("\"{}\"", escape_bytes(&context_text(*context, model.order)));
+                    println!("    {text:<24} {observations:>10} observations, branching {branching}");
+                }
+            }
         }
         "train" => {
-            let input_file_path = args.next().unwrap_or_else(|| {
-                usage(&program);
+            let mut positional = Vec::new();
+            let mut order = None;
+            let mut base_file_path = None;
+            let mut words = false;
+            let mut codepoints = false;
+            let mut max_branching: Option<usize> = None;
+            let mut preprocessing = Preprocessing::default();
+            let mut config = TrainConfig {
+                threads: default_threads(),
+                per_line: false,
+                eos: false,
+                weight: 1,
+                max_records: None,
+                skip_warmup: false,
+                bos: false,
+            };
+            // Weight of every positional argument, in effect at the moment it was passed
+            let mut weight = 1;
+            let mut weights = Vec::new();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => deterministic = true,
+                    "--order" => order = Some(parse_flag_value(&program, "train", &arg, &mut args)),
+                    "--weight" => weight = parse_flag_value(&program, "train", &arg, &mut args),
+                    "--threads" => config.threads = parse_flag_value(&program, "train", &arg, &mut args),
+                    "--max-records" => config.max_records = Some(parse_flag_value(&program, "train", &arg, &mut args)),
+                    "--max-branching" => max_branching = Some(parse_flag_value(&program, "train", &arg, &mut args)),
+                    "--per-line" => config.per_line = true,
+                    "--skip-warmup" => config.skip_warmup = true,
+                    "--bos" => config.bos = true,
+                    "--eos" => config.eos = true,
+                    "--words" => words = true,
+                    "--codepoints" => codepoints = true,
+                    "--lowercase" => preprocessing.lowercase = true,
+                    "--normalize-ws" => preprocessing.normalize_ws = true,
+                    "--strip-control" => preprocessing.strip_control = true,
+                    "--continue" => base_file_path = Some(flag_value(&program, "train", &arg, &mut args)),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "train");
+                        exit(0);
+                    }
+                    _ => {
+                        push_positional(&program, "train", arg, &mut positional);
+                        weights.push(weight);
+                    }
+                }
+            }
+            let output_file_path = positional.pop().unwrap_or_else(|| {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            weights.pop();
+            let input_file_paths = positional;
+            if input_file_paths.is_empty() {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            }
+
+            if weights.contains(&0) {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: weight must be at least 1");
+                exit(1);
+            }
+
+            if (words || codepoints) && config.max_records.is_some() {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --max-records is only supported by the byte-level models");
+                exit(1);
+            }
+            if (words || codepoints) && max_branching.is_some() {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --max-branching is only supported by the byte-level models");
+                exit(1);
+            }
+            if max_branching == Some(0) {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: max branching must be at least 1");
+                exit(1);
+            }
+            if (words || codepoints) && config.skip_warmup {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --skip-warmup is only supported by the byte-level models");
+                exit(1);
+            }
+
+            if config.bos && (!config.per_line || config.skip_warmup) {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --bos requires --per-line and can't be combined with --skip-warmup");
+                exit(1);
+            }
+
+            if config.eos && !config.per_line {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --eos requires --per-line");
+                exit(1);
+            }
+
+            if words && config.per_line {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --words and --per-line can't be used together");
+                exit(1);
+            }
+            if codepoints && (config.per_line || words) {
+                subcommand_usage(&program, "train");
+                eprintln!("ERROR: --codepoints can't be combined with --per-line or --words");
+                exit(1);
+            }
+
+            let max_order = if words || codepoints { MAX_WORD_ORDER } else { MAX_ORDER };
+            if let Some(order) = order {
+                if !(1..=max_order).contains(&order) {
+                    subcommand_usage(&program, "train");
+                    eprintln!("ERROR: order must be within 1..={max_order}, but got {order}");
+                    exit(1);
+                }
+            }
+
+            if words {
+                let mut model = match base_file_path {
+                    Some(base_file_path) => {
+                        println!("Loading the model from {base_file_path}...");
+                        let model = load_word_model(&base_file_path);
+                        if let Some(order) = order {
+                            if order != model.order {
+                                eprintln!("ERROR: {base_file_path} has order {}, but order {order} was requested", model.order);
+                                exit(1);
+                            }
+                        }
+                        if model.preprocessing != preprocessing {
+                            eprintln!("ERROR: {base_file_path} was trained on {} text, but {} was requested", preprocessing_text(model.preprocessing), preprocessing_text(preprocessing));
+                            exit(1);
+                        }
+                        model
+                    }
+                    None => {
+                        let mut model = WordModel::new(order.unwrap_or(2));
+                        model.preprocessing = preprocessing;
+                        model.metadata.created = creation_time(deterministic);
+                        model
+                    }
+                };
+
+                println!("Training the model...");
+                for (input_file_path, weight) in input_file_paths.iter().zip(weights) {
+                    let bytes = read_input(input_file_path).unwrap_or_else(|err| {
+                        eprintln!("ERROR: could not read file {input_file_path}: {err}");
+                        exit(1)
+                    });
+                    let bytes = preprocessing.apply(bytes, ModelKind::Words);
+                    model.metadata.add_training(input_file_path, &config);
+                    model.train(&bytes, weight).unwrap_or_else(|err| {
+                        eprintln!("ERROR: could not train on {input_file_path}: {err}");
+                        exit(1)
+                    });
+                }
+
+                println!("Saving the model to {output_file_path}...");
+                save_word_model(&output_file_path, &model);
+                return
+            }
+
+            if codepoints {
+                let mut model = match base_file_path {
+                    Some(base_file_path) => {
+                        println!("Loading the model from {base_file_path}...");
+                        let model = load_codepoint_model(&base_file_path);
+                        if let Some(order) = order {
+                            if order != model.order {
+                                eprintln!("ERROR: {base_file_path} has order {}, but order {order} was requested", model.order);
+                                exit(1);
+                            }
+                        }
+                        if model.preprocessing != preprocessing {
+                            eprintln!("ERROR: {base_file_path} was trained on {} text, but {} was requested", preprocessing_text(model.preprocessing), preprocessing_text(preprocessing));
+                            exit(1);
+                        }
+                        model
+                    }
+                    None => {
+                        let mut model = CodepointModel::new(order.unwrap_or(MAX_WORD_ORDER));
+                        model.preprocessing = preprocessing;
+                        model.metadata.created = creation_time(deterministic);
+                        model
+                    }
+                };
+
+                println!("Training the model...");
+                for (input_file_path, weight) in input_file_paths.iter().zip(weights) {
+                    let bytes = read_input(input_file_path).unwrap_or_else(|err| {
+                        eprintln!("ERROR: could not read file {input_file_path}: {err}");
+                        exit(1)
+                    });
+                    let bytes = preprocessing.apply(bytes, ModelKind::Codepoints);
+                    model.metadata.add_training(input_file_path, &config);
+                    model.train(&bytes, weight);
+                }
+
+                println!("Saving the model to {output_file_path}...");
+                save_codepoint_model(&output_file_path, &model);
+                return
+            }
+
+            let mut model = match base_file_path {
+                Some(base_file_path) => {
+                    println!("Loading the model from {base_file_path}...");
+                    let model = load_model(&base_file_path);
+                    if let Some(order) = order {
+                        if order != model.order {
+                            eprintln!("ERROR: {base_file_path} has order {}, but order {order} was requested", model.order);
+                            exit(1);
+                        }
+                    }
+                    if model.preprocessing != preprocessing {
+                        eprintln!("ERROR: {base_file_path} was trained on {} text, but {} was requested", preprocessing_text(model.preprocessing), preprocessing_text(preprocessing));
+                        exit(1);
+                    }
+                    model
+                }
+                None => {
+                    let mut model = Model::new(order.unwrap_or(MAX_ORDER));
+                    model.preprocessing = preprocessing;
+                    model.metadata.created = creation_time(deterministic);
+                    model
+                }
+            };
+
+            println!("Training the model...");
+            for (input_file_path, weight) in input_file_paths.iter().zip(weights) {
+                // Read piece by piece, so the input doesn't have to fit into memory
+                let (mut input, size) = open_input(input_file_path).unwrap_or_else(|err| {
+                    eprintln!("ERROR: could not read file {input_file_path}: {err}");
+                    exit(1)
+                });
+                config.weight = weight;
+                model.metadata.add_training(input_file_path, &config);
+                // Only worth it on a terminal, the log files don't need a line per percent
+                let size = size.filter(|size| *size > 0 && io::stderr().is_terminal());
+                let result = if let Some(size) = size {
+                    let shown = AtomicUsize::new(0);
+                    let result = model.train_from_reader(&mut input, &config, |done| {
+                        let percent = std::cmp::min(done as u64*100/size, 100) as usize;
+                        if shown.fetch_max(percent, Ordering::Relaxed) < percent {
+                            eprint!("\r    {input_file_path}: {percent}%");
+                        }
+                    });
+                    eprintln!("\r    {input_file_path}: 100%");
+                    result
+                } else {
+                    model.train_from_reader(&mut input, &config, |_| {})
+                };
+                result.unwrap_or_else(|err| {
+                    eprintln!("ERROR: could not read file {input_file_path}: {err}");
+                    exit(1)
+                });
+            }
+
+            if let Some(max_records) = config.max_records {
+                if model.model.len() >= max_records {
+                    eprintln!("WARNING: the model reached {max_records} records, the contexts that didn't fit were not recorded");
+                }
+            }
+
+            if let Some(max_branching) = max_branching {
+                let size_before = model.serialized_size();
+                let removed_tokens = model.cap_branching(max_branching);
+                println!("Removed tokens beyond --max-branching: {removed_tokens}");
+                println!("Size: {size_before} -> {} bytes", model.serialized_size());
+            }
+
+            println!("Saving the model to {output_file_path}...");
+            save_model(&output_file_path, &model);
+        }
+        "prune" => {
+            let mut positional = Vec::new();
+            let mut min_count = None;
+            let mut max_branching = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--min-count" => min_count = Some(parse_flag_value(&program, "prune", &arg, &mut args)),
+                    "--max-branching" => max_branching = Some(parse_flag_value(&program, "prune", &arg, &mut args)),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "prune");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "prune", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let input_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "prune");
                 eprintln!("ERROR: no input file is provided");
                 exit(1);
             });
-            let output_file_path = args.next().unwrap_or_else(|| {
-                usage(&program);
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "prune");
                 eprintln!("ERROR: no output file is provided");
                 exit(1);
             });
+            expect_no_more_positional(&program, "prune", positional);
+            if min_count.is_none() && max_branching.is_none() {
+                subcommand_usage(&program, "prune");
+                eprintln!("ERROR: no --min-count or --max-branching is provided");
+                exit(1);
+            }
+            if max_branching == Some(0) {
+                subcommand_usage(&program, "prune");
+                eprintln!("ERROR: max branching must be at least 1");
+                exit(1);
+            }
 
-            println!("Training the model...");
-            let mut model = Model::new();
-            let bytes = fs::read(&input_file_path).unwrap_or_else(|err| {
-                eprintln!("ERROR: could not read file {input_file_path}: {err}");
-                exit(1)
+            println!("Loading the model from {input_file_path}...");
+            let mut model = load_model(&input_file_path);
+
+            println!("Pruning the model...");
+            let size_before = model.serialized_size();
+            let (removed_contexts, mut removed_tokens) = model.prune(min_count.unwrap_or(0));
+            if let Some(max_branching) = max_branching {
+                removed_tokens += model.cap_branching(max_branching);
+            }
+            let size_after = model.serialized_size();
+            println!("Removed contexts: {removed_contexts}");
+            println!("Removed tokens: {removed_tokens}");
+            println!("Size: {size_before} -> {size_after} bytes");
+
+            println!("Saving the model to {output_file_path}...");
+            save_model(&output_file_path, &model);
+        }
+        "merge" => {
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--deterministic" => deterministic = true,
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "merge");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "merge", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let a_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "merge");
+                eprintln!("ERROR: no first input file is provided");
+                exit(1);
             });
-            for (context, next) in Slicer::new(bytes) {
-                model.push(context, next)
+            let b_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "merge");
+                eprintln!("ERROR: no second input file is provided");
+                exit(1);
+            });
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "merge");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "merge", positional);
+
+            println!("Loading the model from {a_file_path}...");
+            let mut model = load_model(&a_file_path);
+            println!("Loading the model from {b_file_path}...");
+            let other = load_model(&b_file_path);
+            if model.order != other.order {
+                eprintln!("ERROR: can't merge models of different orders: {a_file_path} has order {}, but {b_file_path} has order {}", model.order, other.order);
+                exit(1);
+            }
+            if model.preprocessing != other.preprocessing {
+                eprintln!("ERROR: can't merge models trained on differently preprocessed text: {a_file_path} and {b_file_path}");
+                exit(1);
             }
 
+            println!("Merging the models...");
+            model.merge(other);
+            model.metadata.created = creation_time(deterministic);
+
             println!("Saving the model to {output_file_path}...");
+            save_model(&output_file_path, &model);
+        }
+        "diff" => {
+            let mut positional = Vec::new();
+            let mut top = 10;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--top" => top = parse_flag_value(&program, "diff", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "diff");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "diff", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let a_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "diff");
+                eprintln!("ERROR: no first input file is provided");
+                exit(1);
+            });
+            let b_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "diff");
+                eprintln!("ERROR: no second input file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "diff", positional);
+
+            eprintln!("Loading the model from {a_file_path}...");
+            let a = load_model(&a_file_path);
+            eprintln!("Loading the model from {b_file_path}...");
+            let b = load_model(&b_file_path);
+            if a.order != b.order {
+                eprintln!("ERROR: can't compare models of different orders: {a_file_path} has order {}, but {b_file_path} has order {}", a.order, b.order);
+                exit(1);
+            }
+
+            let only_a = a.model.keys().filter(|context| !b.model.contains_key(context)).count();
+            let only_b = b.model.keys().filter(|context| !a.model.contains_key(context)).count();
+            let mut changed_contexts = 0;
+            // (context, token, count in A, count in B) of every token whose count differs
+            let mut deltas = Vec::new();
+            for (context, a_freq) in a.sorted() {
+                let Some(b_freq) = b.model.get(&context) else {
+                    continue
+                };
+                let mut tokens: Vec<u8> = a_freq.tokens.iter().chain(b_freq.tokens.iter()).map(|(x, _)| *x).collect();
+                tokens.sort();
+                tokens.dedup();
+                let count = |freq: &Freq, x: u8| freq.tokens.iter().find(|(y, _)| *y == x).map_or(0, |(_, p)| *p as i64);
+                let before = deltas.len();
+                for x in tokens {
+                    let (p, q) = (count(a_freq, x), count(b_freq, x));
+                    if p != q {
+                        deltas.push((context, x, p, q));
+                    }
+                }
+                if deltas.len() > before {
+                    changed_contexts += 1;
+                }
+            }
+            deltas.sort_by_key(|(_, _, p, q)| std::cmp::Reverse((q - p).abs()));
+
+            println!("Contexts only in {a_file_path}: {only_a}");
+            println!("Contexts only in {b_file_path}: {only_b}");
+            println!("Shared contexts: {}", a.model.len() - only_a);
+            println!("Shared contexts with different frequencies: {changed_contexts}");
+            println!("Tokens with different frequencies: {}", deltas.len());
+            if !deltas.is_empty() {
+                println!("Largest differences:");
+                for (context, x, p, q) in deltas.iter().take(top) {
+                    let context = format!("\"{}\"", escape_bytes(&context_text(*context, a.order)));
+                    println!("    {context:<34} {:<6} {p} -> {q} ({:+})", format!("'{}'", escape_bytes(&[*x])), q - p);
+                }
+            }
+        }
+        "export-json" => {
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "export-json");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "export-json", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let input_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "export-json");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "export-json");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "export-json", positional);
+
+            println!("Loading the model from {input_file_path}...");
+            let model = load_model(&input_file_path);
+
+            println!("Exporting the model to {output_file_path}...");
             let output_file = fs::File::create(&output_file_path).unwrap_or_else(|err| {
                 eprintln!("ERROR: could not write file {output_file_path}: {err}");
                 exit(1)
             });
-            model.write_to(&mut io::BufWriter::new(output_file)).unwrap_or_else(|err| {
+            write_json(&model, &mut io::BufWriter::new(output_file)).unwrap_or_else(|err| {
                 eprintln!("ERROR: could not write file {output_file_path}: {err}");
                 exit(1)
             });
         }
+        "export-csv" => {
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "export-csv");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "export-csv", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let input_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "export-csv");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "export-csv");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "export-csv", positional);
+
+            println!("Loading the model from {input_file_path}...");
+            let model = load_model(&input_file_path);
+
+            println!("Exporting the model to {output_file_path}...");
+            let output_file = fs::File::create(&output_file_path).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not write file {output_file_path}: {err}");
+                exit(1)
+            });
+            write_csv(&model, &mut io::BufWriter::new(output_file)).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not write file {output_file_path}: {err}");
+                exit(1)
+            });
+        }
+        "dump" => {
+            let mut positional = Vec::new();
+            let mut max_tokens = 8;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--max-tokens" => max_tokens = parse_flag_value(&program, "dump", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "dump");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "dump", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "dump");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "dump", positional);
+
+            let model = load_model(&file_path);
+            write_dump(&model, max_tokens, &mut io::BufWriter::new(io::stdout().lock())).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not write the dump: {err}");
+                exit(1)
+            });
+        }
+        "dot" => {
+            let mut positional = Vec::new();
+            let mut min_count = 1;
+            let mut max_nodes = 100;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => {}
+                    "--min-count" => min_count = parse_flag_value(&program, "dot", &arg, &mut args),
+                    "--max-nodes" => max_nodes = parse_flag_value(&program, "dot", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "dot");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "dot", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "dot");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "dot", positional);
+
+            let model = load_model(&file_path);
+            write_dot(&model, min_count, max_nodes, &mut io::BufWriter::new(io::stdout().lock())).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not write the graph: {err}");
+                exit(1)
+            });
+        }
+        "import-json" => {
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--deterministic" => deterministic = true,
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "import-json");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "import-json", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let input_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "import-json");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "import-json");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "import-json", positional);
+
+            println!("Importing the model from {input_file_path}...");
+            let bytes = read_input(&input_file_path).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not read file {input_file_path}: {err}");
+                exit(1)
+            });
+            let mut model = json::parse(&bytes).and_then(|json| model_from_json(&json)).unwrap_or_else(|err| {
+                eprintln!("ERROR: {input_file_path}: {err}");
+                exit(1)
+            });
+            model.metadata.created = creation_time(deterministic);
+
+            println!("Saving the model to {output_file_path}...");
+            save_model(&output_file_path, &model);
+        }
+        "import-csv" => {
+            let mut positional = Vec::new();
+            let mut order = MAX_ORDER;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deterministic" => deterministic = true,
+                    "--order" => order = parse_flag_value(&program, "import-csv", &arg, &mut args),
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "import-csv");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "import-csv", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let input_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "import-csv");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "import-csv");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "import-csv", positional);
+
+            if !(1..=MAX_ORDER).contains(&order) {
+                subcommand_usage(&program, "import-csv");
+                eprintln!("ERROR: order must be within 1..={MAX_ORDER}, but got {order}");
+                exit(1);
+            }
+
+            println!("Importing the model from {input_file_path}...");
+            let bytes = read_input(&input_file_path).unwrap_or_else(|err| {
+                eprintln!("ERROR: could not read file {input_file_path}: {err}");
+                exit(1)
+            });
+            let mut model = model_from_csv(&bytes, order).unwrap_or_else(|err| {
+                eprintln!("ERROR: {input_file_path}: {err}");
+                exit(1)
+            });
+            model.metadata.created = creation_time(deterministic);
+
+            println!("Saving the model to {output_file_path}...");
+            save_model(&output_file_path, &model);
+        }
+        "convert-legacy" => {
+            let mut positional = Vec::new();
+            for arg in args {
+                match arg.as_str() {
+                    "--deterministic" => deterministic = true,
+                    "-h" | "--help" => {
+                        subcommand_usage(&program, "convert-legacy");
+                        exit(0);
+                    }
+                    _ => push_positional(&program, "convert-legacy", arg, &mut positional),
+                }
+            }
+            let mut positional = positional.into_iter();
+
+            let input_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "convert-legacy");
+                eprintln!("ERROR: no input file is provided");
+                exit(1);
+            });
+            let output_file_path = positional.next().unwrap_or_else(|| {
+                subcommand_usage(&program, "convert-legacy");
+                eprintln!("ERROR: no output file is provided");
+                exit(1);
+            });
+            expect_no_more_positional(&program, "convert-legacy", positional);
+
+            println!("Loading the legacy model from {input_file_path}...");
+            let mut model = read_model_file(&input_file_path, Model::read_legacy);
+            model.metadata.created = creation_time(deterministic);
+
+            println!("Saving the model to {output_file_path}...");
+            save_model(&output_file_path, &model);
+        }
         _ => {
             usage(&program);
             eprintln!("ERROR: unknown subcommand `{subcommand}`");
@@ -325,3 +2226,77 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::process::{Command, Output};
+
+    // Build the tests next to the binary itself:
+    //   rustc --test --extern carrotson=libcarrotson.rlib carrotson.rs -o cli-tests
+    fn carrotson(args: &[&str]) -> Output {
+        let exe = std::env::current_exe().expect("The tests should know where they are");
+        let program = exe.with_file_name("carrotson");
+        assert!(program.exists(), "{} is not built yet", program.display());
+        Command::new(&program).args(args).output().expect("carrotson should start")
+    }
+
+    // Directory of a single test, removed with everything in it once the test is over
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("carrotson-{}-{test}", std::process::id()));
+            std::fs::create_dir_all(&dir).expect("The temporary directory should be writable");
+            Self(dir)
+        }
+
+        fn file(&self, name: &str) -> String {
+            self.0.join(name).to_str().expect("The temporary directory should be valid UTF-8").to_string()
+        }
+
+        // The tiny text the tests train on
+        fn fixture(&self) -> String {
+            let path = self.file("fixture.txt");
+            std::fs::write(&path, "abracadabra\n").expect("The temporary directory should be writable");
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn stats_reports_the_records() {
+        let dir = TempDir::new("stats");
+        let output = carrotson(&["stats", &dir.fixture()]);
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Every one of the 12 bytes follows a different context of order 8
+        assert!(stdout.lines().any(|line| line == "Records count: 12"), "{}", stdout);
+    }
+
+    #[test]
+    fn gen_with_the_same_seed_is_the_same() {
+        let dir = TempDir::new("gen");
+        let model = dir.file("model.bin");
+        assert!(carrotson(&["train", &dir.fixture(), &model, "--order", "2"]).status.success());
+        let sample = || carrotson(&["gen", &model, "-q", "--seed", "69", "-l", "100"]);
+        let (a, b) = (sample(), sample());
+        assert!(a.status.success());
+        assert!(!a.stdout.is_empty());
+        assert_eq!(a.stdout, b.stdout);
+    }
+
+    #[test]
+    fn train_fails_on_a_missing_input() {
+        let dir = TempDir::new("missing");
+        let output = carrotson(&["train", &dir.file("missing.txt"), &dir.file("model.bin")]);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("ERROR: could not read file"), "{}", stderr);
+    }
+}
@@ -0,0 +1,100 @@
+// Just enough of HTTP/1.1 to answer simple GET requests for `serve`
+
+use std::io::{self, BufRead, Read, Write};
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // Decoded parameters of the query string in the order they appear
+    pub query: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+// Requests are not supposed to have anything big in them, so anything longer is rejected
+// instead of being read into memory
+const MAX_LINE: u64 = 8*1024;
+
+fn read_line(r: &mut impl BufRead) -> Result<String, String> {
+    let mut line = Vec::new();
+    Read::take(&mut *r, MAX_LINE).read_until(b'\n', &mut line).map_err(|err| match err.kind() {
+        // That's how the read timeout of the connection shows up
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => "timed out waiting for the request".to_string(),
+        _ => err.to_string(),
+    })?;
+    if !line.ends_with(b"\n") {
+        return Err("the request is truncated or too long".to_string());
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| "invalid UTF-8 in the request".to_string())
+}
+
+// Decodes %XX escapes and the `+` that stands for a space in the query strings
+fn percent_decode(text: &str) -> Result<String, String> {
+    let bytes = text.as_bytes();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => result.push(b' '),
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                let Some(x) = hex else {
+                    return Err(format!("invalid escape in `{text}`"));
+                };
+                result.push(x);
+                i += 2;
+            }
+            x => result.push(x),
+        }
+        i += 1;
+    }
+    String::from_utf8(result).map_err(|_| format!("invalid UTF-8 in `{text}`"))
+}
+
+// Reads the request line and skips the headers, the body is never needed
+pub fn read_request(r: &mut impl BufRead) -> Result<Request, String> {
+    let line = read_line(r)?;
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("malformed request line `{line}`"));
+    };
+    while !read_line(r)?.is_empty() {}
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut params = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.push((percent_decode(key)?, percent_decode(value)?));
+    }
+    Ok(Request {
+        method: method.to_string(),
+        path: percent_decode(path)?,
+        query: params,
+    })
+}
+
+pub fn write_response(w: &mut impl Write, status: u16, body: &[u8]) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+    write!(w, "HTTP/1.1 {status} {reason}\r\n")?;
+    write!(w, "Content-Type: text/plain; charset=utf-8\r\n")?;
+    write!(w, "Content-Length: {}\r\n", body.len())?;
+    write!(w, "Connection: close\r\n\r\n")?;
+    w.write_all(body)?;
+    w.flush()
+}
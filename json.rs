@@ -0,0 +1,204 @@
+// Just enough of JSON to read back the files produced by `export-json`
+
+// The importer doesn't look at every kind of value, but the parser still has to keep them
+#[allow(dead_code)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    // Kept as text so the full u64 range survives, f64 can't represent all the contexts
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+// Every level of arrays and objects is a level of recursion, so without a limit a file of
+// nothing but `[` would overflow the stack
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    cursor: usize,
+    // Arrays and objects the cursor is inside of
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{message} at byte {}", self.cursor))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.cursor < self.bytes.len() && self.bytes[self.cursor].is_ascii_whitespace() {
+            self.cursor += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.cursor).copied()
+    }
+
+    fn expect(&mut self, x: u8) -> Result<(), String> {
+        if self.peek() != Some(x) {
+            return self.error(&format!("expected `{}`", x as char));
+        }
+        self.cursor += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.bytes[self.cursor..].starts_with(word.as_bytes()) {
+            return self.error("unexpected character");
+        }
+        self.cursor += word.len();
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        if self.depth >= MAX_DEPTH {
+            return self.error("nesting too deep");
+        }
+        self.depth += 1;
+        let value = self.parse_nested();
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_nested(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            None => self.error("unexpected end of input"),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => self.parse_string().map(Json::String),
+            Some(b'[') => {
+                self.cursor += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.cursor += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value()?);
+                    match self.peek() {
+                        Some(b',') => self.cursor += 1,
+                        Some(b']') => {
+                            self.cursor += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return self.error("expected `,` or `]`"),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.cursor += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.cursor += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return self.error("expected a field name");
+                    }
+                    let name = self.parse_string()?;
+                    self.expect(b':')?;
+                    fields.push((name, self.parse_value()?));
+                    match self.peek() {
+                        Some(b',') => self.cursor += 1,
+                        Some(b'}') => {
+                            self.cursor += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return self.error("expected `,` or `}`"),
+                    }
+                }
+            }
+            Some(x) if x == b'-' || x.is_ascii_digit() => {
+                let start = self.cursor;
+                while self.cursor < self.bytes.len() && matches!(self.bytes[self.cursor], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+                    self.cursor += 1;
+                }
+                Ok(Json::Number(String::from_utf8_lossy(&self.bytes[start..self.cursor]).into_owned()))
+            }
+            Some(_) => self.error("unexpected character"),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut result = Vec::new();
+        loop {
+            let Some(x) = self.bytes.get(self.cursor).copied() else {
+                return self.error("unterminated string");
+            };
+            self.cursor += 1;
+            match x {
+                b'"' => break,
+                b'\\' => {
+                    let Some(escape) = self.bytes.get(self.cursor).copied() else {
+                        return self.error("unterminated string");
+                    };
+                    self.cursor += 1;
+                    match escape {
+                        b'"' => result.push(b'"'),
+                        b'\\' => result.push(b'\\'),
+                        b'/' => result.push(b'/'),
+                        b'b' => result.push(0x08),
+                        b'f' => result.push(0x0C),
+                        b'n' => result.push(b'\n'),
+                        b'r' => result.push(b'\r'),
+                        b't' => result.push(b'\t'),
+                        b'u' => {
+                            let code = self.bytes.get(self.cursor..self.cursor + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+                            let Some(code) = code else {
+                                return self.error("invalid unicode escape");
+                            };
+                            self.cursor += 4;
+                            let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => return self.error("invalid escape sequence"),
+                    }
+                }
+                _ => result.push(x),
+            }
+        }
+        String::from_utf8(result).or_else(|_| self.error("invalid UTF-8 in a string"))
+    }
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Json, String> {
+    let mut parser = Parser { bytes, cursor: 0, depth: 0 };
+    let value = parser.parse_value()?;
+    if parser.peek().is_some() {
+        return parser.error("unexpected trailing characters");
+    }
+    Ok(value)
+}
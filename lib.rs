@@ -0,0 +1,2463 @@
+//! Markov model implementation on sequence of bytes.
+//!
+//! Build with `rustc --crate-type=rlib --crate-name carrotson lib.rs`, test with
+//! `rustc --test --crate-name carrotson lib.rs -o lib-tests && ./lib-tests`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Source of randomness for all the sampling.
+pub trait Rng {
+    fn next_u32(&mut self) -> u32;
+
+    /// Two draws of `next_u32`, the first one being the high half.
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64)<<32 | self.next_u32() as u64
+    }
+
+    /// Uniformly random number within `[0, 1)` with all 53 bits of the mantissa random.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64()>>11) as f64/(1u64<<53) as f64
+    }
+
+    /// Uniformly random number below `n`, which must not be 0. Unlike `next_u32() % n` it
+    /// doesn't favor the smaller numbers when `n` doesn't divide the range evenly.
+    fn below(&mut self, n: u64) -> u64 {
+        if n <= 1<<32 {
+            // The values below this one would make the smallest results one draw more
+            // likely than the rest, so they are drawn again
+            let threshold = (1<<32)%n;
+            loop {
+                let x = self.next_u32() as u64;
+                if x >= threshold {
+                    return x%n
+                }
+            }
+        }
+        let threshold = n.wrapping_neg()%n;
+        loop {
+            let x = self.next_u64();
+            if x >= threshold {
+                return x%n
+            }
+        }
+    }
+}
+
+/// Linear congruential generator, the default `Rng`.
+pub struct LCG {
+    state: u64
+}
+
+impl LCG {
+    pub fn new(seed: u64) -> Self {
+        Self {state: seed}
+    }
+
+    pub fn random_u32(&mut self) -> u32 {
+        // Stolen from https://en.wikipedia.org/wiki/Linear_congruential_generator
+        // Using the values of MMIX by Donald Knuth
+        const RAND_A: u64 = 6364136223846793005;
+        const RAND_C: u64 = 1442695040888963407;
+        (self.state, _) = self.state.overflowing_mul(RAND_A);
+        (self.state, _) = self.state.overflowing_add(RAND_C);
+        return (self.state>>32) as u32;
+    }
+
+    /// Advances the state twice and combines the two high halves, the low bits of the
+    /// state itself are too predictable to be used. Same as `Rng::next_u64`.
+    pub fn random_u64(&mut self) -> u64 {
+        self.next_u64()
+    }
+
+    /// Uniformly random number within `[0, 1)`. Same as `Rng::next_f64`.
+    pub fn random_f64(&mut self) -> f64 {
+        self.next_f64()
+    }
+}
+
+impl Rng for LCG {
+    fn next_u32(&mut self) -> u32 {
+        self.random_u32()
+    }
+}
+
+/// Marsaglia's xorshift64. Unlike `LCG` all of its bits are equally good, not just the
+/// high ones.
+pub struct XorShift {
+    state: u64
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // Zero state would only ever produce zeros
+        Self {state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed }}
+    }
+}
+
+impl Rng for XorShift {
+    fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state<<13;
+        self.state ^= self.state>>7;
+        self.state ^= self.state<<17;
+        (self.state>>32) as u32
+    }
+}
+
+/// Frequencies of the tokens that followed a particular context. The tokens are bytes
+/// unless the model says otherwise, like `WordModel` does.
+#[derive(Debug, Clone)]
+pub struct Freq<T = u8> {
+    /// Pairs of a token and the amount of times it was seen
+    pub tokens: Vec<(T, u32)>,
+}
+
+impl<T: Copy + Ord> Default for Freq<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_u8(r: &mut impl io::Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(u8::from_le_bytes(buf))
+}
+
+fn read_u32(r: &mut impl io::Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl io::Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// Reads `len` bytes without trusting `len` enough to allocate all of them upfront, so a
+// corrupt length can't take all the memory before the end of the file is noticed
+fn read_bytes(r: &mut impl io::Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    Read::take(&mut *r, len).read_to_end(&mut result)?;
+    if (result.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(result)
+}
+
+/// Adds `delta` to a token count saturating at `u32::MAX`.
+///
+/// All the places that combine token counts must go through this, so a hot token on a
+/// highly repetitive corpus doesn't wrap around.
+pub fn add_count(count: &mut u32, delta: u32) {
+    *count = count.saturating_add(delta);
+}
+
+impl<T: Copy + Ord> Freq<T> {
+    /// Amount of distinct bytes that followed the context.
+    pub fn branching(&self) -> usize {
+        return self.tokens.len();
+    }
+
+    pub fn new() -> Self {
+        Self { tokens: Vec::new() }
+    }
+
+    /// Records one more occurrence of `x`.
+    pub fn push(&mut self, x: T) {
+        self.push_n(x, 1)
+    }
+
+    /// Records `n` more occurrences of `x`.
+    pub fn push_n(&mut self, x: T, n: u32) {
+        let mut found = false;
+        for (y, p) in self.tokens.iter_mut() {
+            if *y == x {
+                add_count(p, n);
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            self.tokens.push((x, n))
+        }
+    }
+
+    /// Shannon entropy of the distribution in bits. 0.0 means the next byte is always the
+    /// same, 8.0 means it's completely random.
+    pub fn entropy(&self) -> f64 {
+        let sum: u64 = self.tokens.iter().map(|(_, p)| *p as u64).sum();
+        if sum == 0 {
+            return 0.0
+        }
+        -self.tokens.iter().filter(|(_, p)| *p > 0).map(|(_, p)| {
+            let p = *p as f64/sum as f64;
+            p*p.log2()
+        }).sum::<f64>()
+    }
+
+    /// Picks a byte proportionally to its frequency.
+    pub fn random(&self, rng: &mut impl Rng) -> Option<T> {
+        let sum: u64 = self.tokens.iter().map(|(_, p)| *p as u64).sum();
+
+        if sum > 0 {
+            let index = rng.below(sum);
+            let mut psum: u64 = 0;
+            for (y, p) in self.tokens.iter() {
+                psum += *p as u64;
+                if psum > index {
+                    return Some(*y)
+                }
+            }
+        }
+        None
+    }
+
+    /// Picks a byte proportionally to its frequency raised to the power of `1/t`.
+    ///
+    /// Temperature `t` of 1.0 is the same distribution as `random`, values below 1.0
+    /// sharpen it toward the most frequent byte, values above 1.0 flatten it toward uniform.
+    /// At 0.0 it's no longer random and picks the same byte as `argmax`.
+    pub fn random_with_temperature(&self, rng: &mut impl Rng, t: f64) -> Option<T> {
+        self.random_with_penalty(rng, t, &[], 1.0)
+    }
+
+    /// Same as `random_with_temperature`, but the weights of the bytes from `penalized` are
+    /// additionally divided by `penalty`.
+    pub fn random_with_penalty(&self, rng: &mut impl Rng, t: f64, penalized: &[T], penalty: f64) -> Option<T> {
+        self.random_with_penalties(rng, t, |x| if penalized.contains(&x) { penalty } else { 1.0 })
+    }
+
+    /// Same as `random_with_temperature`, but the weight of every token `x` is additionally
+    /// divided by `penalty(x)`.
+    pub fn random_with_penalties(&self, rng: &mut impl Rng, t: f64, penalty: impl Fn(T) -> f64) -> Option<T> {
+        if t == 0.0 {
+            // The limit of the distribution as `t` goes to 0, which `powf(1.0/t)` can't compute
+            let weight = |(x, p): &(T, u32)| *p as f64/penalty(*x);
+            return self.tokens.iter()
+                .max_by(|a, b| weight(a).total_cmp(&weight(b)).then(b.0.cmp(&a.0)))
+                .map(|(x, _)| *x)
+        }
+        let weights: Vec<f64> = self.tokens.iter().map(|(x, p)| {
+            (*p as f64).powf(1.0/t)/penalty(*x)
+        }).collect();
+        let sum: f64 = weights.iter().sum();
+
+        if sum > 0.0 {
+            let index = (rng.next_u32() as f64)/(u32::MAX as f64 + 1.0)*sum;
+            let mut psum = 0.0;
+            for ((y, _), w) in self.tokens.iter().zip(weights.iter()) {
+                psum += w;
+                if psum > index {
+                    return Some(*y)
+                }
+            }
+            // Rounding errors may leave the index right at the end of the scan
+            return self.tokens.last().map(|(y, _)| *y)
+        }
+        None
+    }
+
+    /// Probability of `x` according to the frequencies, None if `x` was never seen.
+    pub fn probability(&self, x: T) -> Option<f64> {
+        let sum: f64 = self.tokens.iter().map(|(_, p)| *p as f64).sum();
+        self.tokens.iter().find(|(y, _)| *y == x).map(|(_, p)| *p as f64/sum)
+    }
+
+    /// The most frequent byte, the lowest one among equally frequent bytes.
+    pub fn argmax(&self) -> Option<T> {
+        self.tokens.iter().max_by(|(x, p), (y, q)| p.cmp(q).then(y.cmp(x))).map(|(x, _)| *x)
+    }
+
+    /// Picks a byte uniformly ignoring the frequencies.
+    pub fn random_flat(&self, rng: &mut impl Rng) -> Option<T> {
+        if self.tokens.is_empty() {
+            return None
+        }
+        let index = rng.below(self.tokens.len() as u64) as usize;
+        Some(self.tokens[index].0)
+    }
+
+    /// Adds the counts of `other` to this one.
+    pub fn merge(&mut self, other: &Freq<T>) {
+        for (x, q) in other.tokens.iter() {
+            match self.tokens.iter_mut().find(|(y, _)| y == x) {
+                Some((_, p)) => add_count(p, *q),
+                None => self.tokens.push((*x, *q)),
+            }
+        }
+    }
+
+    /// The `k` most frequent bytes with their probabilities, most likely first.
+    pub fn top_k(&self, k: usize) -> Vec<(T, f64)> {
+        let sum: u64 = self.tokens.iter().map(|(_, p)| *p as u64).sum();
+        self.most_frequent(k).tokens.into_iter().map(|(x, p)| (x, p as f64/sum as f64)).collect()
+    }
+
+    /// Only the `k` most frequent bytes, most frequent first. Ties are broken by the lowest byte.
+    pub fn most_frequent(&self, k: usize) -> Freq<T> {
+        let mut tokens = self.tokens.clone();
+        tokens.sort_by(|(x1, p1), (x2, p2)| p2.cmp(p1).then(x1.cmp(x2)));
+        tokens.truncate(k);
+        Freq { tokens }
+    }
+
+    /// Picks a byte proportionally to its frequency among the `k` most frequent ones only.
+    pub fn random_top_k(&self, rng: &mut impl Rng, k: usize) -> Option<T> {
+        self.most_frequent(k).random(rng)
+    }
+
+    /// The smallest set of the most frequent bytes whose total probability reaches `p`.
+    pub fn nucleus(&self, p: f64) -> Freq<T> {
+        let sum: u64 = self.tokens.iter().map(|(_, q)| *q as u64).sum();
+        let mut result = self.most_frequent(self.tokens.len());
+        let mut psum: u64 = 0;
+        let mut len = 0;
+        for (_, q) in result.tokens.iter() {
+            psum += *q as u64;
+            len += 1;
+            if psum as f64 >= p*sum as f64 {
+                break
+            }
+        }
+        result.tokens.truncate(len);
+        result
+    }
+
+    /// Nucleus sampling: picks a byte proportionally to its frequency among the smallest set
+    /// of the most frequent bytes whose total probability reaches `p`.
+    pub fn random_top_p(&self, rng: &mut impl Rng, p: f64) -> Option<T> {
+        self.nucleus(p).random(rng)
+    }
+}
+
+impl Freq {
+    /// Picks any of the 256 bytes proportionally to its frequency plus `k`, so even the
+    /// bytes that never followed the context have a small chance (add-k smoothing).
+    pub fn random_smoothed(&self, rng: &mut impl Rng, k: f64) -> Option<u8> {
+        let mut weights = [k; 256];
+        for (y, p) in self.tokens.iter() {
+            weights[*y as usize] += *p as f64;
+        }
+        let sum: f64 = weights.iter().sum();
+
+        if sum > 0.0 {
+            let index = (rng.next_u32() as f64)/(u32::MAX as f64 + 1.0)*sum;
+            let mut psum = 0.0;
+            for (y, w) in weights.iter().enumerate() {
+                psum += w;
+                if psum > index {
+                    return Some(y as u8)
+                }
+            }
+            // Rounding errors may leave the index right at the end of the scan
+            return weights.iter().rposition(|w| *w > 0.0).map(|y| y as u8)
+        }
+        None
+    }
+
+    /// Writes the tokens prefixed by their amount, which takes a single byte with 0 meaning
+    /// all 256 of them. So an empty `Freq` can't be written, `Model::write_to` leaves the
+    /// empty contexts out.
+    pub fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        if self.tokens.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "an empty Freq can't be written"));
+        }
+        // 256 wraps around to 0
+        w.write_all(&(self.tokens.len() as u8).to_le_bytes())?;
+        for (x, p) in self.tokens.iter() {
+            w.write_all(&x.to_le_bytes())?;
+            w.write_all(&p.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
+        Self::read_versioned(r, MODEL_VERSION)
+    }
+
+    // Same as `read_from`, but for the model format `version`
+    fn read_versioned(r: &mut impl io::Read, version: u8) -> io::Result<Self> {
+        let mut result = Self::new();
+        let count = match read_u8(r)? {
+            0 if version > MODEL_VERSION_EMPTY_FREQS => 256,
+            count => count as usize,
+        };
+        for _ in 0..count {
+            let x = read_u8(r)?;
+            let p = read_u32(r)?;
+            result.tokens.push((x, p));
+        }
+        Ok(result)
+    }
+}
+
+
+/// Precomputed Vose's alias tables of a `Freq` that pick a byte with the same distribution
+/// as `Freq::random`, but in constant time regardless of the amount of bytes.
+pub struct AliasTable {
+    tokens: Vec<u8>,
+    // Chance out of 2^32 to pick the own byte of a column rather than its alias
+    thresholds: Vec<u64>,
+    alias: Vec<usize>,
+}
+
+impl AliasTable {
+    pub fn new(freq: &Freq) -> Self {
+        let sum: f64 = freq.tokens.iter().map(|(_, p)| *p as f64).sum();
+        if sum == 0.0 {
+            // Nothing to pick, same as `Freq::random`
+            return Self {tokens: Vec::new(), thresholds: Vec::new(), alias: Vec::new()}
+        }
+        let n = freq.tokens.len();
+        let mut prob: Vec<f64> = freq.tokens.iter().map(|(_, p)| *p as f64*n as f64/sum).collect();
+        let mut alias: Vec<usize> = (0..n).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|i| prob[*i] < 1.0);
+        while let (Some(&s), Some(&l)) = (small.last(), large.last()) {
+            small.pop();
+            large.pop();
+            alias[s] = l;
+            prob[l] += prob[s] - 1.0;
+            if prob[l] < 1.0 {
+                small.push(l);
+            } else {
+                large.push(l);
+            }
+        }
+        // Whatever is left is full up to rounding errors
+        for i in small.into_iter().chain(large) {
+            prob[i] = 1.0;
+        }
+        Self {
+            tokens: freq.tokens.iter().map(|(x, _)| *x).collect(),
+            thresholds: prob.iter().map(|p| (p*(1u64<<32) as f64) as u64).collect(),
+            alias,
+        }
+    }
+
+    pub fn random(&self, rng: &mut impl Rng) -> Option<u8> {
+        if self.tokens.is_empty() {
+            return None
+        }
+        // The high half picks the column, the low half decides between it and its alias
+        let m = rng.next_u32() as u64*self.tokens.len() as u64;
+        let column = (m>>32) as usize;
+        if (m&0xFFFFFFFF) < self.thresholds[column] {
+            Some(self.tokens[column])
+        } else {
+            Some(self.tokens[self.alias[column]])
+        }
+    }
+}
+
+/// The context is packed into a u64, so it can't remember more than 8 bytes.
+pub const MAX_ORDER: u8 = 8;
+
+pub const MODEL_MAGIC: &[u8; 4] = b"CRSN";
+/// `ModelInfo::version` of the models of the original carrotson, which have no header at
+/// all, see `Model::read_legacy`.
+pub const MODEL_VERSION_LEGACY: u8 = 0;
+pub const MODEL_VERSION: u8 = 6;
+// The last version without the checksum at the end, still readable
+const MODEL_VERSION_UNCHECKED: u8 = 1;
+// The last version without `ModelKind` in the header, which only had byte-level models
+const MODEL_VERSION_BYTES_ONLY: u8 = 2;
+// The last version without `Preprocessing` in the header
+const MODEL_VERSION_UNPROCESSED: u8 = 3;
+// The last version without `Metadata` after the header
+const MODEL_VERSION_NO_METADATA: u8 = 4;
+// The last version where the amount of tokens of 0 in `Freq::write_to` meant none rather
+// than all 256 of them, so a context followed by every possible byte couldn't be written
+const MODEL_VERSION_EMPTY_FREQS: u8 = 5;
+
+/// How the text was transformed before training. Recorded in the model file, so the text
+/// the model is later given, like the prompt, can be transformed the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Preprocessing {
+    /// ASCII letters were lowercased, or all of them for `ModelKind::Codepoints`
+    pub lowercase: bool,
+    /// Runs of spaces, tabs and carriage returns were collapsed into a single space, and the
+    /// ones right before a newline were dropped
+    pub normalize_ws: bool,
+    /// Bytes 0x00..=0x1F other than newlines and tabs were dropped
+    pub strip_control: bool,
+}
+
+impl Preprocessing {
+    /// Transforms `bytes` the way the text of a model of this `kind` was.
+    pub fn apply(&self, bytes: Vec<u8>, kind: ModelKind) -> Vec<u8> {
+        let mut bytes = bytes;
+        // Before the whitespace normalization, which would otherwise see the spaces around
+        // a dropped byte as separate runs
+        if self.strip_control {
+            bytes.retain(|x| *x >= 0x20 || *x == b'\n' || *x == b'\t');
+        }
+        if self.lowercase {
+            if kind == ModelKind::Codepoints {
+                bytes = String::from_utf8_lossy(&bytes).to_lowercase().into_bytes();
+            } else {
+                bytes.make_ascii_lowercase();
+            }
+        }
+        if self.normalize_ws {
+            let mut result = Vec::with_capacity(bytes.len());
+            let mut space = false;
+            for x in bytes.into_iter() {
+                match x {
+                    b' ' | b'\t' | b'\r' | 0x0B | 0x0C => space = true,
+                    b'\n' => {
+                        space = false;
+                        result.push(x);
+                    }
+                    _ => {
+                        if space {
+                            result.push(b' ');
+                            space = false;
+                        }
+                        result.push(x);
+                    }
+                }
+            }
+            if space {
+                result.push(b' ');
+            }
+            bytes = result;
+        }
+        bytes
+    }
+
+    /// Names of the transformations that are on.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut result = Vec::new();
+        if self.lowercase {
+            result.push("lowercase");
+        }
+        if self.normalize_ws {
+            result.push("normalized whitespace");
+        }
+        if self.strip_control {
+            result.push("no control characters");
+        }
+        result
+    }
+
+    fn to_u8(self) -> u8 {
+        self.lowercase as u8 | (self.normalize_ws as u8) << 1 | (self.strip_control as u8) << 2
+    }
+
+    fn from_u8(flags: u8) -> io::Result<Self> {
+        if flags & !0b111 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown preprocessing flags {flags:#04x}")));
+        }
+        Ok(Self { lowercase: flags & 1 != 0, normalize_ws: flags & 0b10 != 0, strip_control: flags & 0b100 != 0 })
+    }
+}
+
+/// Where the model came from. Nothing in the library looks at it, it's only there so
+/// a model file can tell how it was made.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    /// Seconds since the Unix epoch when the model was created, 0 if unknown
+    pub created: u64,
+    /// Names of the files the model was trained on, in the order they were trained on
+    pub sources: Vec<String>,
+    /// Whether any of the training was done with `TrainConfig::per_line`
+    pub per_line: bool,
+    /// Same as `per_line` for `TrainConfig::eos`
+    pub eos: bool,
+    /// Same as `per_line` for `TrainConfig::bos`
+    pub bos: bool,
+    /// Same as `per_line` for `TrainConfig::skip_warmup`
+    pub skip_warmup: bool,
+}
+
+// Sources are just file names, anything longer than that means the file is corrupt
+const MAX_SOURCE_LEN: u32 = 64*1024;
+
+impl Metadata {
+    /// Records that the model was trained on `source` with `config`.
+    pub fn add_training(&mut self, source: &str, config: &TrainConfig) {
+        self.sources.push(source.to_string());
+        self.per_line |= config.per_line;
+        self.eos |= config.eos;
+        self.bos |= config.bos;
+        self.skip_warmup |= config.skip_warmup;
+    }
+
+    /// Records that the model was merged with the one `other` describes.
+    pub fn merge(&mut self, other: &Metadata) {
+        self.sources.extend(other.sources.iter().cloned());
+        self.per_line |= other.per_line;
+        self.eos |= other.eos;
+        self.bos |= other.bos;
+        self.skip_warmup |= other.skip_warmup;
+    }
+
+    /// Names of the training modes that are on.
+    pub fn mode_names(&self) -> Vec<&'static str> {
+        let mut result = Vec::new();
+        if self.per_line {
+            result.push("per-line");
+        }
+        if self.eos {
+            result.push("eos");
+        }
+        if self.bos {
+            result.push("bos");
+        }
+        if self.skip_warmup {
+            result.push("skip-warmup");
+        }
+        result
+    }
+
+    fn serialized_size(&self) -> usize {
+        1 + 8 + 4 + self.sources.iter().map(|source| 4 + source.len()).sum::<usize>()
+    }
+
+    fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let flags = self.per_line as u8 | (self.eos as u8) << 1 | (self.bos as u8) << 2 | (self.skip_warmup as u8) << 3;
+        w.write_all(&flags.to_le_bytes())?;
+        w.write_all(&self.created.to_le_bytes())?;
+        w.write_all(&(self.sources.len() as u32).to_le_bytes())?;
+        for source in self.sources.iter() {
+            w.write_all(&(source.len() as u32).to_le_bytes())?;
+            w.write_all(source.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
+        let flags = read_u8(r)?;
+        if flags & !0b1111 != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown training mode flags {flags:#04x}")));
+        }
+        let created = read_u64(r)?;
+        let count = read_u32(r)?;
+        let mut sources = Vec::new();
+        for _ in 0..count {
+            let len = read_u32(r)?;
+            if len > MAX_SOURCE_LEN {
+                return Err(corrupt_model());
+            }
+            let source = read_bytes(r, len as u64)?;
+            sources.push(String::from_utf8(source).map_err(|_| corrupt_model())?);
+        }
+        Ok(Self {
+            created,
+            sources,
+            per_line: flags & 1 != 0,
+            eos: flags & 0b10 != 0,
+            bos: flags & 0b100 != 0,
+            skip_warmup: flags & 0b1000 != 0,
+        })
+    }
+}
+
+/// What the tokens of the model stored in a file are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModelKind {
+    /// `Model`
+    Bytes = 0,
+    /// `WordModel`
+    Words = 1,
+    /// `CodepointModel`
+    Codepoints = 2,
+}
+
+impl ModelKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ModelKind::Bytes => "byte-level",
+            ModelKind::Words => "word-level",
+            ModelKind::Codepoints => "codepoint-level",
+        }
+    }
+}
+
+fn expect_kind(kind: ModelKind, expected: ModelKind) -> io::Result<()> {
+    if kind != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("this is a {} model, only {} ones are supported here", kind.name(), expected.name())));
+    }
+    Ok(())
+}
+
+fn write_header(w: &mut impl io::Write, kind: ModelKind, order: u8, preprocessing: Preprocessing, metadata: &Metadata) -> io::Result<()> {
+    w.write_all(MODEL_MAGIC)?;
+    w.write_all(&MODEL_VERSION.to_le_bytes())?;
+    w.write_all(&(kind as u8).to_le_bytes())?;
+    w.write_all(&order.to_le_bytes())?;
+    w.write_all(&preprocessing.to_u8().to_le_bytes())?;
+    metadata.write_to(w)
+}
+
+// Amount of bytes `write_header` is going to produce
+fn header_size(metadata: &Metadata) -> usize {
+    MODEL_MAGIC.len() + 1 + 1 + 1 + 1 + metadata.serialized_size()
+}
+
+/// Everything a model file tells before the model itself.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    /// Format version the file was written in
+    pub version: u8,
+    pub kind: ModelKind,
+    pub order: u8,
+    pub preprocessing: Preprocessing,
+    /// `None` if the format version predates it
+    pub metadata: Option<Metadata>,
+}
+
+fn read_header(r: &mut impl io::Read) -> io::Result<ModelInfo> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MODEL_MAGIC {
+        // Which is all that tells the legacy models apart, the rest of them is only checked
+        // once the whole file is read
+        return Ok(ModelInfo {
+            version: MODEL_VERSION_LEGACY,
+            kind: ModelKind::Bytes,
+            order: MAX_ORDER,
+            preprocessing: Preprocessing::default(),
+            metadata: None,
+        });
+    }
+    let version = read_u8(r)?;
+    if !(MODEL_VERSION_UNCHECKED..=MODEL_VERSION).contains(&version) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported model format version {version}, expected {MODEL_VERSION}")));
+    }
+    let kind = if version <= MODEL_VERSION_BYTES_ONLY {
+        ModelKind::Bytes
+    } else {
+        match read_u8(r)? {
+            0 => ModelKind::Bytes,
+            1 => ModelKind::Words,
+            2 => ModelKind::Codepoints,
+            kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown model kind {kind}"))),
+        }
+    };
+    let order = read_u8(r)?;
+    let preprocessing = if version <= MODEL_VERSION_UNPROCESSED {
+        Preprocessing::default()
+    } else {
+        Preprocessing::from_u8(read_u8(r)?)?
+    };
+    let metadata = if version <= MODEL_VERSION_NO_METADATA {
+        None
+    } else {
+        Some(Metadata::read_from(r).map_err(|err| if err.kind() == io::ErrorKind::UnexpectedEof { corrupt_model() } else { err })?)
+    };
+    Ok(ModelInfo { version, kind, order, preprocessing, metadata })
+}
+
+/// Reads just enough of a model file written by `Model::write_to` or `WordModel::write_to`
+/// to tell which one of them it is.
+pub fn read_model_kind(r: &mut impl io::Read) -> io::Result<ModelKind> {
+    read_header(r).map(|info| info.kind)
+}
+
+/// Reads the header of a model file of any kind without the model itself. Anything
+/// without a header is taken for a legacy model, only `Model::read_legacy` can tell for sure.
+pub fn read_model_info(r: &mut impl io::Read) -> io::Result<ModelInfo> {
+    read_header(r)
+}
+
+// The most contexts `Model::read_from` makes room for upfront
+const MAX_RESERVE: u64 = 1<<20;
+
+fn corrupt_model() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "model file is corrupt or truncated")
+}
+
+// Reads whatever `body` reads and the checksum after it, if `version` has one
+fn read_checked<R: io::Read>(r: &mut Checksum<R>, version: u8, body: impl FnOnce(&mut Checksum<R>) -> io::Result<()>) -> io::Result<()> {
+    body(r).map_err(|err| if err.kind() == io::ErrorKind::UnexpectedEof { corrupt_model() } else { err })?;
+    if version != MODEL_VERSION_UNCHECKED {
+        let hash = r.hash;
+        if read_u64(&mut r.inner).ok() != Some(hash) {
+            return Err(corrupt_model());
+        }
+    }
+    Ok(())
+}
+
+// Passes the bytes through while computing their FNV-1a hash, which is simple and good
+// enough to tell a damaged model file from an intact one
+struct Checksum<T> {
+    inner: T,
+    hash: u64,
+}
+
+impl<T> Checksum<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, hash: 0xcbf29ce484222325 }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for x in bytes.iter() {
+            self.hash ^= *x as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for Checksum<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: io::Read> io::Read for Checksum<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Byte recorded at the end of every line when training with `TrainConfig::eos`. Texts
+/// rarely contain it, so it's free to mean "the text is over".
+pub const EOS: u8 = 0;
+
+/// Byte the contexts at the beginning of every line are padded with when training with
+/// `TrainConfig::bos`, instead of the zero bytes. Never appears in valid UTF-8.
+pub const BOS: u8 = 0xFF;
+
+/// Context of the very beginning of a line of a model trained with `TrainConfig::bos`.
+pub fn bos_context(order: u8) -> u64 {
+    context_mask(order)
+}
+
+/// Parameters of `Model::train`.
+pub struct TrainConfig {
+    /// Amount of threads to train on
+    pub threads: usize,
+    /// Treat every line as an independent text, so contexts don't span across lines and
+    /// newlines themselves are not learned
+    pub per_line: bool,
+    /// With `per_line`, record `EOS` after the last byte of every non-empty line, so
+    /// generation can learn where the texts end
+    pub eos: bool,
+    /// Amount of times every byte is recorded, so a small text can outweigh a bigger one
+    pub weight: u32,
+    /// Once the model has this many contexts, stop adding new ones and only keep counting
+    /// the known ones. Which contexts make it depends on the order they are seen in, so
+    /// this makes the training run on a single thread.
+    pub max_records: Option<usize>,
+    /// Don't record the first `Model::order` bytes of the text (or of every line with
+    /// `per_line`), whose contexts are padded with zero bytes. The model then doesn't know
+    /// context 0, so `generate` starts from a random context instead, see
+    /// `GenConfig::random_start`.
+    pub skip_warmup: bool,
+    /// With `per_line`, pad the contexts at the beginning of every line with `BOS` rather
+    /// than zero bytes, so they can't be confused with a real run of zero bytes
+    pub bos: bool,
+}
+
+/// Maps every context seen during training to the frequencies of the bytes that followed it.
+#[derive(Debug)]
+pub struct Model {
+    /// Amount of preceding bytes that make up a context
+    pub order: u8,
+    /// Frequencies of the bytes that followed every known context
+    pub model: HashMap<u64, Freq>,
+    /// What was done to the text before training, it's up to the caller to do it
+    pub preprocessing: Preprocessing,
+    /// Where the model came from, it's up to the caller to fill it in
+    pub metadata: Metadata,
+}
+
+impl Model {
+    pub fn new(order: u8) -> Self {
+        Self {
+            order,
+            model: HashMap::new(),
+            preprocessing: Preprocessing::default(),
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// Context the text starts with: `bos_context` for the models trained with
+    /// `TrainConfig::bos`, 0 for the rest.
+    pub fn start_context(&self) -> u64 {
+        let bos = bos_context(self.order);
+        if self.model.contains_key(&bos) { bos } else { 0 }
+    }
+
+    /// Frequencies of the bytes that followed any of the known contexts ending with the
+    /// same `len` bytes as `context`, which is what a model of order `len` would learn.
+    /// Goes through the whole model, see `SuffixIndex::suffix_freq` for the repeated lookups.
+    pub fn suffix_freq(&self, context: u64, len: u8) -> Freq {
+        let mask = context_mask(len);
+        let mut result = Freq::new();
+        for (_, freq) in self.model.iter().filter(|(c, _)| *c&mask == context&mask) {
+            result.merge(freq);
+        }
+        // The contexts are visited in a random order, don't let it leak into the sampling
+        result.tokens.sort_by_key(|(x, _)| *x);
+        result
+    }
+
+    /// Probability of `next` following `context`, None if that was never seen. With
+    /// `backoff` falls back to the shorter and shorter suffixes of `context`, see `suffix_freq`.
+    pub fn probability(&self, context: u64, next: u8, backoff: bool) -> Option<f64> {
+        let p = self.model.get(&context).and_then(|freq| freq.probability(next));
+        if p.is_some() || !backoff {
+            return p
+        }
+        (1..self.order).rev().find_map(|len| self.suffix_freq(context, len).probability(next))
+    }
+
+    /// Same as `probability` with `backoff`, but also tells how many times it had to back
+    /// off: 0 if `context` itself was followed by `next`, 1 if only its suffix one byte
+    /// shorter was, and so on. The suffixes are looked up through `index`.
+    pub fn backoff_probability(&self, index: &SuffixIndex, context: u64, next: u8) -> Option<(f64, u8)> {
+        (0..std::cmp::max(self.order, 1)).find_map(|steps| {
+            let p = if steps == 0 {
+                self.model.get(&context).and_then(|freq| freq.probability(next))
+            } else {
+                index.suffix_freq(self, context, self.order - steps).probability(next)
+            };
+            p.map(|p| (p, steps))
+        })
+    }
+
+    /// Records that `next` followed `context`.
+    pub fn push(&mut self, context: u64, next: u8) {
+        self.push_n(context, next, 1)
+    }
+
+    /// Records that `next` followed `context` `n` times.
+    pub fn push_n(&mut self, context: u64, next: u8, n: u32) {
+        self.model.entry(context).or_default().push_n(next, n)
+    }
+
+    /// Records every byte of `bytes` along with the context that preceded it.
+    ///
+    /// `bytes` is split into chunks that are processed on `config.threads` threads and
+    /// merged afterwards. The result doesn't depend on the amount of threads.
+    pub fn train(&mut self, bytes: &[u8], config: &TrainConfig) {
+        self.train_with_progress(bytes, config, |_| {})
+    }
+
+    /// Same as `train`, but every `PROGRESS_STEP` bytes or so calls `progress` with the
+    /// amount of bytes of `bytes` processed so far. It's called from the training threads.
+    pub fn train_with_progress(&mut self, bytes: &[u8], config: &TrainConfig, progress: impl Fn(usize) + Sync) {
+        self.train_after(bytes, 0, config, progress)
+    }
+
+    /// Same as `train_with_progress`, but reads the text from `r` `TRAIN_CHUNK` bytes at a
+    /// time instead of needing all of it in memory, and applies `Model::preprocessing` to
+    /// it. `progress` is called with the amount of bytes read from `r` so far.
+    pub fn train_from_reader(&mut self, r: &mut impl io::Read, config: &TrainConfig, progress: impl Fn(usize) + Sync) -> io::Result<()> {
+        let order = self.order as usize;
+        let mut chunk = Vec::new();
+        // The last bytes of the text trained on so far, which the contexts at the beginning
+        // of the next chunk are made of
+        let mut history = Vec::new();
+        let mut read = 0;
+        loop {
+            Read::take(&mut *r, (TRAIN_CHUNK - chunk.len()) as u64).read_to_end(&mut chunk)?;
+            let eof = chunk.len() < TRAIN_CHUNK;
+            // Cutting right after a newline keeps the lines whole for `TrainConfig::per_line`
+            // and the runs of whitespace whole for `Preprocessing::normalize_ws`. Only a line
+            // longer than the whole chunk gets cut anywhere else.
+            let cut = if eof { chunk.len() } else { chunk.iter().rposition(|x| *x == b'\n').map_or(chunk.len(), |i| i + 1) };
+            let rest = chunk.split_off(cut);
+            let raw = chunk.len();
+            let text = self.preprocessing.apply(std::mem::replace(&mut chunk, rest), ModelKind::Bytes);
+            let first = if config.per_line { 0 } else { history.len() };
+            let mut bytes = if config.per_line { Vec::new() } else { std::mem::take(&mut history) };
+            bytes.extend_from_slice(&text);
+            let processed = text.len().max(1);
+            self.train_after(&bytes, first, config, |done| progress(read + done*raw/processed));
+            read += raw;
+            if eof {
+                return Ok(())
+            }
+            history = bytes[bytes.len().saturating_sub(order)..].to_vec();
+        }
+    }
+
+    // Trains on `bytes[first..]`, the bytes before it being the end of the text that was
+    // already trained on. `progress` is called with the amount of bytes of `bytes[first..]`
+    // processed so far.
+    fn train_after(&mut self, bytes: &[u8], first: usize, config: &TrainConfig, progress: impl Fn(usize) + Sync) {
+        let threads = if config.max_records.is_some() { 1 } else { config.threads.max(1) };
+        let chunk_size = (bytes.len() - first).div_ceil(threads).max(1);
+        let mut bounds = vec![first];
+        while let Some(&start) = bounds.last() {
+            if start >= bytes.len() {
+                break
+            }
+            let mut end = std::cmp::min(start + chunk_size, bytes.len());
+            if config.per_line {
+                // Lines are independent, so cutting right after a newline means the chunks
+                // don't have to know anything about each other
+                end = bytes[end..].iter().position(|x| *x == b'\n').map_or(bytes.len(), |i| end + i + 1);
+            }
+            bounds.push(end);
+        }
+        let order = self.order;
+        let base = &*self;
+        let max_records = config.max_records.unwrap_or(usize::MAX);
+        let done = AtomicUsize::new(0);
+        let report = |n: usize| progress(done.fetch_add(n, Ordering::Relaxed) + n);
+        let report = &report;
+        let partials: Vec<Model> = thread::scope(|scope| {
+            let handles: Vec<_> = bounds.windows(2).map(|bound| {
+                let (start, end) = (bound[0], bound[1]);
+                scope.spawn(move || {
+                    let mut partial = Trainer { model: Model::new(order), indices: HashMap::new(), base, new_records: 0, max_records };
+                    let mut reported = 0;
+                    if config.per_line {
+                        let mut processed = 0;
+                        for line in bytes[start..end].split(|x| *x == b'\n') {
+                            // The last line of the chunk may not end with a newline
+                            processed = std::cmp::min(processed + line.len() + 1, end - start);
+                            if processed - reported >= PROGRESS_STEP {
+                                report(processed - reported);
+                                reported = processed;
+                            }
+                            let start = if config.bos { bos_context(order) } else { 0 };
+                            let mut last = start;
+                            let skip = if config.skip_warmup { order as usize } else { 0 };
+                            for (context, next) in Slicer::starting_at(line, order, start).skip(skip) {
+                                partial.push_n(context, next, config.weight);
+                                last = context;
+                                context_push(&mut last, next, order);
+                            }
+                            if config.eos && line.len() > skip {
+                                partial.push_n(last, EOS, config.weight);
+                            }
+                        }
+                    } else {
+                        // Start a bit earlier so the window of the chunk is seeded with the
+                        // bytes that precede it, but don't record those bytes twice
+                        let warmup = std::cmp::min(start, order as usize);
+                        let skip = if config.skip_warmup { order as usize } else { warmup };
+                        for (i, (context, next)) in Slicer::new(&bytes[start - warmup..end], order).skip(skip).enumerate() {
+                            partial.push_n(context, next, config.weight);
+                            if (i + 1) % PROGRESS_STEP == 0 {
+                                report(PROGRESS_STEP);
+                                reported += PROGRESS_STEP;
+                            }
+                        }
+                    }
+                    report(end - start - reported);
+                    partial.model
+                })
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().expect("Training thread should not panic")).collect()
+        });
+        // Merging in the order of the chunks keeps the tokens in the order of their first
+        // appearance, just like sequential training does
+        for partial in partials {
+            self.merge(partial);
+        }
+    }
+
+    /// Adds all the contexts and counts of `other` to this model, and its sources to the
+    /// metadata.
+    pub fn merge(&mut self, other: Model) {
+        self.metadata.merge(&other.metadata);
+        for (context, freq) in other.model.into_iter() {
+            match self.model.get_mut(&context) {
+                Some(existing) => existing.merge(&freq),
+                None => {
+                    self.model.insert(context, freq);
+                }
+            }
+        }
+    }
+
+    /// Removes the tokens that were seen less than `min_count` times along with the
+    /// contexts that are left without any tokens. Returns the amount of removed
+    /// contexts and tokens.
+    pub fn prune(&mut self, min_count: u32) -> (usize, usize) {
+        let mut removed_tokens = 0;
+        for freq in self.model.values_mut() {
+            let before = freq.tokens.len();
+            freq.tokens.retain(|(_, p)| *p >= min_count);
+            removed_tokens += before - freq.tokens.len();
+        }
+        let before = self.model.len();
+        self.model.retain(|_, freq| !freq.tokens.is_empty());
+        (before - self.model.len(), removed_tokens)
+    }
+
+    /// Keeps only the `k` most frequent tokens of every context, with the ties broken like
+    /// in `Freq::most_frequent`. Returns the amount of removed tokens.
+    pub fn cap_branching(&mut self, k: usize) -> usize {
+        let mut removed_tokens = 0;
+        for freq in self.model.values_mut() {
+            if freq.tokens.len() > k {
+                removed_tokens += freq.tokens.len() - k;
+                // The kept tokens stay in the order they were in
+                let kept = freq.most_frequent(k);
+                freq.tokens.retain(|(x, _)| kept.tokens.iter().any(|(y, _)| x == y));
+            }
+        }
+        self.model.retain(|_, freq| !freq.tokens.is_empty());
+        removed_tokens
+    }
+
+    /// Amount of bytes `write_to` is going to produce.
+    pub fn serialized_size(&self) -> usize {
+        let header = header_size(&self.metadata) + 8;
+        let checksum = 8;
+        header + self.model.values().filter(|freq| !freq.tokens.is_empty()).map(|freq| 8 + 1 + freq.tokens.len()*5).sum::<usize>() + checksum
+    }
+
+    /// Rough estimate of the memory the model takes, as a pair of the bytes the contexts and
+    /// their tokens actually need and the bytes allocated for them, including the spare
+    /// capacity of the `HashMap` and of every `Freq`. Doesn't know about the allocator overhead.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        let token = std::mem::size_of::<(u8, u32)>();
+        // The entries of the table along with a control byte each
+        let entry = std::mem::size_of::<(u64, Freq)>() + 1;
+        let useful = self.model.values().map(|freq| 8 + freq.tokens.len()*5).sum::<usize>();
+        let allocated = self.model.capacity()*entry + self.model.values().map(|freq| freq.tokens.capacity()*token).sum::<usize>();
+        (useful, allocated)
+    }
+
+    /// All the contexts with their frequencies ordered by the context, unlike iterating
+    /// `model` directly which visits them in a random order.
+    pub fn sorted(&self) -> Vec<(u64, &Freq)> {
+        let mut result: Vec<(u64, &Freq)> = self.model.iter().map(|(context, freq)| (*context, freq)).collect();
+        result.sort_by_key(|(context, _)| *context);
+        result
+    }
+
+    /// Serializes the model in the format `read_from` understands. The same model always
+    /// produces the same bytes, the last 8 of which are the checksum of all the others.
+    pub fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let mut w = Checksum::new(w);
+        write_header(&mut w, ModelKind::Bytes, self.order, self.preprocessing, &self.metadata)?;
+        // There is no way to write an empty `Freq`, and nothing would be lost without it anyway
+        let contexts: Vec<(u64, &Freq)> = self.sorted().into_iter().filter(|(_, freq)| !freq.tokens.is_empty()).collect();
+        w.write_all(&(contexts.len() as u64).to_le_bytes())?;
+        for (context, freq) in contexts {
+            w.write_all(&context.to_le_bytes())?;
+            freq.write_to(&mut w)?;
+        }
+        let hash = w.hash;
+        w.inner.write_all(&hash.to_le_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Deserializes the model written by `write_to`, or the legacy one `read_legacy` reads
+    /// if there is no header. Whatever is neither of them is an error.
+    pub fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MODEL_MAGIC {
+            return Self::read_legacy(&mut Read::chain(&magic[..], r));
+        }
+        let mut r = Checksum::new(Read::chain(&magic[..], r));
+        let ModelInfo { version, kind, order, preprocessing, metadata } = read_header(&mut r)?;
+        expect_kind(kind, ModelKind::Bytes)?;
+        if !(1..=MAX_ORDER).contains(&order) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported model order {order}")));
+        }
+        let mut result = Self::new(order);
+        result.preprocessing = preprocessing;
+        result.metadata = metadata.unwrap_or_default();
+        read_checked(&mut r, version, |r| {
+            let count = read_u64(r)?;
+            // The count comes from the file, which may be corrupt. Beyond this the map just
+            // grows as the contexts are actually read.
+            result.model.reserve(std::cmp::min(count, MAX_RESERVE) as usize);
+            for _ in 0..count {
+                let context = read_u64(r)?;
+                let freq = Freq::read_versioned(r, version)?;
+                result.model.insert(context, freq);
+            }
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Deserializes the models of the original carrotson, which have no header: a u64
+    /// amount of contexts, each of them a u64 followed by its `Freq` the way `Freq::write_to`
+    /// writes it. Those are all of order `MAX_ORDER`. There is no checksum either, so
+    /// anything that doesn't add up to exactly that is rejected rather than guessed at.
+    pub fn read_legacy(r: &mut impl io::Read) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        if bytes.starts_with(MODEL_MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "this is a model file of the current format, not a legacy one"));
+        }
+        let mut result = Self::new(MAX_ORDER);
+        let valid = read_legacy_contexts(&mut bytes.as_slice(), &mut result.model);
+        if !valid.unwrap_or(false) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "neither a carrotson model file nor a legacy one"));
+        }
+        Ok(result)
+    }
+}
+
+// The contexts of `Model::read_legacy`. False if `r` turns out to be something else, even
+// if it's perfectly readable.
+fn read_legacy_contexts(r: &mut &[u8], model: &mut HashMap<u64, Freq>) -> io::Result<bool> {
+    let count = read_u64(r)?;
+    model.reserve(std::cmp::min(count, MAX_RESERVE) as usize);
+    for _ in 0..count {
+        let context = read_u64(r)?;
+        // It never wrote an empty `Freq`, so a count of 0 is 256 wrapped around
+        let freq = Freq::read_versioned(r, MODEL_VERSION)?;
+        let mut tokens: Vec<u8> = freq.tokens.iter().map(|(x, _)| *x).collect();
+        tokens.sort();
+        tokens.dedup();
+        if tokens.len() < freq.tokens.len() || model.insert(context, freq).is_some() {
+            return Ok(false)
+        }
+    }
+    Ok(r.is_empty())
+}
+
+/// The known contexts of a `Model` grouped by their suffixes, so looking them up doesn't
+/// go through the whole model every time. Built on first use, so it's up to the caller to
+/// always pass the same model, and not to change it meanwhile.
+#[derive(Debug, Default)]
+pub struct SuffixIndex {
+    // All the known contexts, sorted, so the same seed picks the same context
+    contexts: OnceLock<Vec<u64>>,
+    // For every `len` below the order of the model, the known contexts by their last
+    // `len` bytes at `len - 1`, sorted as well
+    suffixes: OnceLock<Vec<HashMap<u64, Vec<u64>>>>,
+}
+
+impl SuffixIndex {
+    fn contexts(&self, model: &Model) -> &[u64] {
+        self.contexts.get_or_init(|| {
+            let mut contexts: Vec<u64> = model.model.keys().copied().collect();
+            contexts.sort();
+            contexts
+        })
+    }
+
+    // Known contexts ending with the same `len` bytes as `context`
+    fn candidates<'a>(&'a self, model: &Model, context: u64, len: u8) -> &'a [u64] {
+        if len == 0 {
+            return self.contexts(model)
+        }
+        if len >= model.order {
+            let context = context&context_mask(model.order);
+            let contexts = self.contexts(model);
+            return match contexts.binary_search(&context) {
+                Ok(i) => &contexts[i..=i],
+                Err(_) => &[],
+            }
+        }
+        let suffixes = self.suffixes.get_or_init(|| {
+            let mut suffixes: Vec<HashMap<u64, Vec<u64>>> = vec![HashMap::new(); model.order.saturating_sub(1) as usize];
+            for &context in self.contexts(model) {
+                for (i, group) in suffixes.iter_mut().enumerate() {
+                    group.entry(context&context_mask(i as u8 + 1)).or_default().push(context);
+                }
+            }
+            suffixes
+        });
+        suffixes[len as usize - 1].get(&(context&context_mask(len))).map(|c| c.as_slice()).unwrap_or(&[])
+    }
+
+    /// Picks a uniformly random known context of `model`.
+    pub fn random_context(&self, model: &Model, rng: &mut impl Rng) -> Option<u64> {
+        let contexts = self.contexts(model);
+        if contexts.is_empty() {
+            return None
+        }
+        Some(contexts[rng.below(contexts.len() as u64) as usize])
+    }
+
+    /// Finds a known context of `model` that shares the longest possible suffix of at most
+    /// `len` bytes with `context`. If several of them do, picks a random one.
+    pub fn find_by_suffix(&self, model: &Model, context: u64, len: usize, rng: &mut impl Rng) -> Option<u64> {
+        for len in (1..=std::cmp::min(len, model.order as usize)).rev() {
+            let candidates = self.candidates(model, context, len as u8);
+            if !candidates.is_empty() {
+                return Some(candidates[rng.below(candidates.len() as u64) as usize]);
+            }
+        }
+        None
+    }
+
+    /// Same as `Model::suffix_freq`, but only visits the contexts that do end that way.
+    pub fn suffix_freq(&self, model: &Model, context: u64, len: u8) -> Freq {
+        let mut result = Freq::new();
+        for c in self.candidates(model, context, len) {
+            result.merge(&model.model[c]);
+        }
+        result.tokens.sort_by_key(|(x, _)| *x);
+        result
+    }
+}
+
+// Contexts with at least this many different continuations get a `Trainer` index
+const INDEXED_BRANCHING: usize = 16;
+
+/// Roughly how often `Model::train_with_progress` reports the progress, in bytes.
+pub const PROGRESS_STEP: usize = 1<<20;
+
+/// Amount of bytes `Model::train_from_reader` reads at once.
+pub const TRAIN_CHUNK: usize = 64<<20;
+
+// Model under training. Contexts with a lot of different continuations also get an index
+// from a byte to its position in `Freq::tokens` (or u16::MAX if it's not there), so pushing
+// into them doesn't have to scan all the tokens. The index is too big to have it for every
+// context.
+struct Trainer<'a> {
+    model: Model,
+    indices: HashMap<u64, Box<[u16; 256]>>,
+    // The model the result is going to be merged into, for `TrainConfig::max_records`
+    base: &'a Model,
+    // Contexts of `model` that `base` doesn't know
+    new_records: usize,
+    max_records: usize,
+}
+
+impl Trainer<'_> {
+    fn push_n(&mut self, context: u64, next: u8, n: u32) {
+        if self.max_records != usize::MAX && !self.model.model.contains_key(&context) && !self.base.model.contains_key(&context) {
+            if self.base.model.len() + self.new_records >= self.max_records {
+                return
+            }
+            self.new_records += 1;
+        }
+        let freq = self.model.model.entry(context).or_default();
+        if freq.tokens.len() < INDEXED_BRANCHING {
+            freq.push_n(next, n);
+            if freq.tokens.len() == INDEXED_BRANCHING {
+                let mut index = Box::new([u16::MAX; 256]);
+                for (i, (x, _)) in freq.tokens.iter().enumerate() {
+                    index[*x as usize] = i as u16;
+                }
+                self.indices.insert(context, index);
+            }
+            return
+        }
+        let index = self.indices.get_mut(&context).expect("Every context with enough continuations should be indexed");
+        match index[next as usize] {
+            u16::MAX => {
+                index[next as usize] = freq.tokens.len() as u16;
+                freq.tokens.push((next, n));
+            }
+            i => add_count(&mut freq.tokens[i as usize].1, n),
+        }
+    }
+}
+
+/// Walks over the bytes yielding every byte along with the context that preceded it.
+pub struct Slicer<B: AsRef<[u8]>> {
+    bytes: B,
+    order: u8,
+    window: u64,
+    cursor: usize,
+}
+
+impl<B: AsRef<[u8]>> Slicer<B> {
+    pub fn new(bytes: B, order: u8) -> Self {
+        Self::starting_at(bytes, order, 0)
+    }
+
+    /// Same as `new`, but the context of the first byte is `context` instead of 0.
+    pub fn starting_at(bytes: B, order: u8, context: u64) -> Self {
+        Self{bytes, order, window: context, cursor: 0}
+    }
+}
+
+impl<B: AsRef<[u8]>> Iterator for Slicer<B> {
+    type Item = (u64, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.bytes.as_ref();
+        if self.cursor >= bytes.len() {
+            return None
+        }
+
+        let result = self.window;
+        let next = bytes[self.cursor];
+        context_push(&mut self.window, next, self.order);
+        self.cursor += 1;
+
+        return Some((result, next));
+    }
+}
+
+/// Mask that keeps only the last `order` bytes of a context.
+pub fn context_mask(order: u8) -> u64 {
+    u64::MAX>>(8*(MAX_ORDER - order))
+}
+
+/// The bytes of the context from the oldest to the most recent one.
+pub fn context_bytes(context: u64, order: u8) -> Vec<u8> {
+    context.to_be_bytes()[(MAX_ORDER - order) as usize..].to_vec()
+}
+
+/// Appends `x` to the context forgetting the bytes older than `order`.
+pub fn context_push(context: &mut u64, x: u8, order: u8) {
+    *context = (((*context)<<8)|(x as u64))&context_mask(order);
+}
+
+// Amount of continuation bytes that follow the leading byte `x` of a UTF-8 sequence.
+// None if `x` can't start a sequence at all.
+fn utf8_continuation_len(x: u8) -> Option<usize> {
+    match x {
+        0x00..=0x7F => Some(0),
+        0xC2..=0xDF => Some(1),
+        0xE0..=0xEF => Some(2),
+        0xF0..=0xF4 => Some(3),
+        _ => None,
+    }
+}
+
+/// Parameters of `generate`.
+#[derive(Clone)]
+pub struct GenConfig {
+    /// Maximum amount of bytes generated after the prompt. 0 means no limit, in which case
+    /// the dead ends are restarted from instead of ending the text, see `generate_streaming`
+    pub limit: usize,
+    /// Text the result starts with and continues from
+    pub prompt: String,
+    /// Pick uniformly among the known continuations ignoring their frequencies
+    pub flatten_counts: bool,
+    /// Never emit a byte that would start or continue a UTF-8 sequence that can't be completed
+    pub utf8_boundary: bool,
+    /// Reshapes the distribution, see `Freq::random_with_temperature`
+    pub temperature: f64,
+    /// Sample only among this many most frequent continuations
+    pub top_k: Option<usize>,
+    /// Sample only among the most frequent continuations that cover this much probability
+    pub top_p: Option<f64>,
+    /// Stop as soon as the generated text ends with these bytes, unless empty
+    pub stop: Vec<u8>,
+    /// Don't include `stop` into the result
+    pub trim_stop: bool,
+    /// Instead of stopping on a dead end before this many bytes were generated, continue
+    /// from a context that shares a shorter suffix with the current one
+    pub min_length: usize,
+    /// Stop when `EOS` is sampled, without including it into the result. Makes sense only
+    /// for the models trained with `TrainConfig::eos`
+    pub eos: bool,
+    /// When the context has no continuations, sample from the contexts that share a
+    /// shorter and shorter suffix with it before giving up (Katz-style backoff)
+    pub backoff: bool,
+    /// Pseudo-count added to every possible byte, see `Freq::random_smoothed`. Disabled
+    /// when zero
+    pub smoothing: f64,
+    /// Without a prompt, start from a random known context instead of the beginning of the
+    /// text. The result starts with the bytes of that context. Models that don't know the
+    /// beginning of the text always start like that.
+    pub random_start: bool,
+    /// Always pick the most frequent continuation instead of sampling, see `Freq::argmax`
+    pub greedy: bool,
+    /// Instead of sampling, look for the most probable text keeping this many candidates,
+    /// see `beam_search`
+    pub beam: Option<usize>,
+    /// Divides the weights of the bytes among the last `REPETITION_WINDOW` bytes of the
+    /// text by this, so values above 1.0 discourage loops. Disabled when 1.0
+    pub repetition_penalty: f64,
+    /// When the same `MAX_ORDER` bytes occur `CYCLE_REPEATS` times among the last
+    /// `CYCLE_WINDOW` bytes of the text, jump to a random context that ends with the same
+    /// byte, so the text doesn't get stuck repeating itself
+    pub cycle_guard: bool,
+    /// Stop before the whitespace that follows this many generated words, which are the
+    /// runs of anything but ASCII whitespace. `limit` still applies. Disabled when zero
+    pub word_limit: usize,
+    /// The first generated bytes of the texts generated so far in a batch, as returned by
+    /// `generate_streaming`. Every time a byte occurs here divides its weight as the first
+    /// generated byte once more, so the texts of the batch don't all open the same way
+    pub used_starts: Vec<u8>,
+}
+
+/// Amount of the last bytes of the text `GenConfig::repetition_penalty` looks at.
+pub const REPETITION_WINDOW: usize = 32;
+
+/// Amount of the last bytes of the text `GenConfig::cycle_guard` looks at.
+pub const CYCLE_WINDOW: usize = 256;
+/// How many times the same bytes have to occur among the last `CYCLE_WINDOW` bytes to be
+/// considered a cycle by `GenConfig::cycle_guard`.
+pub const CYCLE_REPEATS: usize = 16;
+
+// Maximum amount of times `generate` restarts from a different context after a dead end
+// while trying to reach `GenConfig::min_length`, so it can't loop forever on an empty model
+const MAX_RESTARTS: usize = 64;
+
+// Picks the next byte out of `freq` according to all the sampling options of `config`.
+// `pending` is the amount of continuation bytes the last UTF-8 sequence is still waiting
+// for, `remaining` is the amount of bytes that still can be generated and `recent` are
+// the last bytes of the text.
+// `used` are the bytes to down-weight like `GenConfig::used_starts`.
+fn sample_next(freq: &Freq, config: &GenConfig, pending: usize, remaining: usize, recent: &[u8], used: &[u8], rng: &mut impl Rng) -> Option<u8> {
+    let mut freq = Cow::Borrowed(freq);
+    if config.utf8_boundary {
+        freq = Cow::Owned(Freq {
+            tokens: freq.tokens.iter().copied().filter(|(x, _)| {
+                if pending > 0 {
+                    (0x80..=0xBF).contains(x)
+                } else {
+                    utf8_continuation_len(*x).is_some_and(|n| n < remaining)
+                }
+            }).collect()
+        });
+    }
+    let freq = truncate_tokens(freq, config);
+    if !used.is_empty() {
+        let temperature = if config.greedy { 0.0 } else { config.temperature };
+        return freq.random_with_penalties(rng, temperature, |x| {
+            let penalty = if recent.contains(&x) { config.repetition_penalty } else { 1.0 };
+            penalty*(1 + used.iter().filter(|y| **y == x).count()) as f64
+        })
+    }
+    if config.smoothing > 0.0 && !config.greedy {
+        freq.random_smoothed(rng, config.smoothing)
+    } else {
+        pick_token(&freq, config, recent, rng)
+    }
+}
+
+// Leaves only the tokens of `freq` allowed by `GenConfig::top_k` and `GenConfig::top_p`.
+fn truncate_tokens<'a, T: Copy + Ord>(mut freq: Cow<'a, Freq<T>>, config: &GenConfig) -> Cow<'a, Freq<T>> {
+    if let Some(k) = config.top_k {
+        freq = Cow::Owned(freq.most_frequent(k));
+    }
+    if let Some(p) = config.top_p {
+        freq = Cow::Owned(freq.nucleus(p));
+    }
+    freq
+}
+
+// Picks a token out of `freq` the way the sampling options of `config` that make sense
+// for any kind of tokens say. `recent` are the last tokens of the text.
+fn pick_token<T: Copy + Ord>(freq: &Freq<T>, config: &GenConfig, recent: &[T], rng: &mut impl Rng) -> Option<T> {
+    if config.greedy {
+        freq.argmax()
+    } else if config.flatten_counts {
+        freq.random_flat(rng)
+    } else if config.repetition_penalty != 1.0 {
+        freq.random_with_penalty(rng, config.temperature, recent, config.repetition_penalty)
+    } else if config.temperature != 1.0 {
+        freq.random_with_temperature(rng, config.temperature)
+    } else {
+        freq.random(rng)
+    }
+}
+
+// Candidate text of `beam_search`
+struct Beam {
+    bytes: Vec<u8>,
+    context: u64,
+    // Sum of the natural logarithms of the probabilities of all the generated bytes
+    score: f64,
+    // Dead-ended, reached `GenConfig::stop` or `EOS`, so can't be continued anymore
+    finished: bool,
+}
+
+// Continues `buffer`, whose last bytes make up `context`, with the most probable text of at
+// most `GenConfig::limit` bytes. After every byte only the `width` most probable candidates are
+// kept. Only the stopping options of `config` are respected, it doesn't sample anything.
+fn beam_search(model: &Model, config: &GenConfig, context: u64, buffer: Vec<u8>, width: usize) -> Vec<u8> {
+    let start = buffer.len();
+    let mut beams = vec![Beam {bytes: buffer, context, score: 0.0, finished: false}];
+    for _ in 0..config.limit {
+        let mut candidates = Vec::new();
+        for beam in beams.into_iter() {
+            let freq = model.model.get(&beam.context).filter(|freq| !freq.tokens.is_empty());
+            let Some(freq) = freq.filter(|_| !beam.finished) else {
+                candidates.push(Beam {finished: true, ..beam});
+                continue
+            };
+            let sum: f64 = freq.tokens.iter().map(|(_, p)| *p as f64).sum();
+            // Less frequent continuations can't make it into the best `width` anyway
+            for (x, p) in freq.most_frequent(width).tokens.iter() {
+                let score = beam.score + (*p as f64/sum).ln();
+                let mut bytes = beam.bytes.clone();
+                let mut context = beam.context;
+                let mut finished = false;
+                if config.eos && *x == EOS {
+                    finished = true;
+                } else {
+                    bytes.push(*x);
+                    context_push(&mut context, *x, model.order);
+                    if !config.stop.is_empty() && bytes[start..].ends_with(&config.stop) {
+                        if config.trim_stop {
+                            bytes.truncate(bytes.len() - config.stop.len());
+                        }
+                        finished = true;
+                    }
+                }
+                candidates.push(Beam {bytes, context, score, finished});
+            }
+        }
+        // Ties are broken by the text itself, so the result doesn't depend on the order of the tokens
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.bytes.cmp(&b.bytes)));
+        candidates.truncate(width);
+        beams = candidates;
+        if beams.iter().all(|beam| beam.finished) {
+            break
+        }
+    }
+    beams.into_iter().next().map(|beam| beam.bytes).unwrap_or_default()
+}
+
+/// Generates a random text according to the model.
+pub fn generate(model: &Model, config: &GenConfig, rng: &mut impl Rng) -> Vec<u8> {
+    let mut result = Vec::new();
+    generate_streaming(model, &SuffixIndex::default(), config, rng, |bytes| result.extend_from_slice(bytes));
+    result
+}
+
+// Amount of bytes `generate_streaming` lets accumulate before getting rid of the ones that
+// were already emitted
+const STREAM_CHUNK: usize = 4096;
+
+/// Same as `generate`, but hands the text over to `emit` piece by piece as soon as it's
+/// known those bytes are going to stay in the result, instead of returning it at once.
+/// Only a bounded amount of the text is kept in memory, so with `config.limit` of 0 it can
+/// keep going forever, restarting from a similar context at every dead end. `index` is
+/// worth to keep around for all the texts generated from the same `model`. Returns the
+/// first generated byte, if any, for `GenConfig::used_starts`.
+pub fn generate_streaming(model: &Model, index: &SuffixIndex, config: &GenConfig, rng: &mut impl Rng, mut emit: impl FnMut(&[u8])) -> Option<u8> {
+    let start = model.start_context();
+    let mut context = start;
+    let mut buffer = Vec::new();
+    for x in config.prompt.bytes() {
+        buffer.push(x);
+        context_push(&mut context, x, model.order);
+    }
+    // Nothing to start from at the beginning of the text of a model trained with
+    // `TrainConfig::skip_warmup`
+    let random_start = config.random_start || !model.model.contains_key(&start);
+    if random_start && config.prompt.is_empty() {
+        if let Some(random) = index.random_context(model, rng) {
+            context = random;
+            // Leading zero bytes (or `BOS` ones) are the padding of the contexts at the very
+            // beginning of the text
+            let padding = if start == 0 { 0 } else { BOS };
+            buffer.extend(context_bytes(context, model.order).into_iter().skip_while(|x| *x == padding));
+        }
+    }
+    if !model.model.contains_key(&context) {
+        // The prompt as a whole was never seen, continue from something that ends similarly
+        context = index.find_by_suffix(model, context, config.prompt.len(), rng).unwrap_or(start);
+    }
+    if let Some(width) = config.beam {
+        emit(&beam_search(model, config, context, buffer, width));
+        return None
+    }
+    let mut generated = 0;
+    // Everything before this in `buffer` was already emitted
+    let mut emitted = 0;
+    // The last bytes may turn out to be the beginning of `stop`, which is going to be trimmed
+    let held = if config.trim_stop { config.stop.len().saturating_sub(1) } else { 0 };
+    // The last bytes that have to stay in `buffer` even after being emitted, for the stop
+    // check and the repetition penalty
+    let kept = std::cmp::max(REPETITION_WINDOW, config.stop.len());
+    // Continuation bytes the last emitted UTF-8 sequence is still waiting for
+    let mut pending = 0;
+    let infinite = config.limit == 0;
+    // Consecutive ones when `infinite`, so only a model that has nowhere to go at all ends it
+    let mut restarts = 0;
+    // Without any of the sampling options every context is sampled the same way every
+    // time, so it's worth to prepare that once
+    let plain = !config.utf8_boundary && config.top_k.is_none() && config.top_p.is_none()
+        && !config.greedy && config.smoothing == 0.0 && !config.flatten_counts
+        && config.repetition_penalty == 1.0 && config.temperature == 1.0;
+    let mut tables: HashMap<u64, AliasTable> = HashMap::new();
+    // The suffixes `config.backoff` fell back to so far
+    let mut suffix_freqs: HashMap<(u8, u64), Freq> = HashMap::new();
+    // The last `MAX_ORDER` bytes of the text after each of the last `CYCLE_WINDOW` bytes,
+    // and how many times each of them occurs there. Not the context itself, a context of a
+    // low order occurs all the time in any text.
+    let mut tail = 0;
+    let mut tails = VecDeque::new();
+    let mut tail_counts: HashMap<u64, usize> = HashMap::new();
+    // Words generated so far and whether the last generated byte is in the middle of one,
+    // for `GenConfig::word_limit`
+    let mut words = 0;
+    let mut in_word = false;
+    let mut first = None;
+    while infinite || generated < config.limit {
+        let remaining = if infinite { usize::MAX } else { config.limit - generated };
+        let recent = &buffer[buffer.len().saturating_sub(REPETITION_WINDOW)..];
+        let used: &[u8] = if generated == 0 { &config.used_starts } else { &[] };
+        let mut x = match model.model.get(&context) {
+            Some(freq) if plain && used.is_empty() => tables.entry(context).or_insert_with(|| AliasTable::new(freq)).random(rng),
+            Some(freq) => sample_next(freq, config, pending, remaining, recent, used, rng),
+            None => None,
+        };
+        if x.is_none() && config.backoff {
+            for len in (1..model.order).rev() {
+                let freq = suffix_freqs.entry((len, context&context_mask(len)))
+                    .or_insert_with(|| index.suffix_freq(model, context, len));
+                if freq.tokens.is_empty() {
+                    // Nothing to sample from, not even with smoothing
+                    continue
+                }
+                x = sample_next(freq, config, pending, remaining, recent, used, rng);
+                if x.is_some() {
+                    break
+                }
+            }
+        }
+        // The end of the text is as good as a dead end
+        let Some(x) = x.filter(|x| !(config.eos && *x == EOS)) else {
+            if (infinite || generated < config.min_length) && restarts < MAX_RESTARTS {
+                restarts += 1;
+                context = index.find_by_suffix(model, context, model.order as usize - 1, rng).unwrap_or(start);
+                continue
+            }
+            break
+        };
+        if x.is_ascii_whitespace() {
+            if in_word {
+                words += 1;
+                if words == config.word_limit {
+                    break
+                }
+            }
+            in_word = false;
+        } else {
+            in_word = true;
+        }
+        buffer.push(x);
+        if generated == 0 {
+            first = Some(x);
+        }
+        generated += 1;
+        if infinite {
+            restarts = 0;
+        }
+        context_push(&mut context, x, model.order);
+        if config.cycle_guard {
+            context_push(&mut tail, x, MAX_ORDER);
+            tails.push_back(tail);
+            let count = tail_counts.entry(tail).or_insert(0);
+            *count += 1;
+            let cycle = *count >= CYCLE_REPEATS;
+            if tails.len() > CYCLE_WINDOW {
+                let old = tails.pop_front().expect("The window can't be empty after a push");
+                if let Some(count) = tail_counts.get_mut(&old) {
+                    *count -= 1;
+                }
+            }
+            if cycle {
+                // Ending with the same byte, so the text still reads on at least a little
+                context = index.find_by_suffix(model, context, 1, rng).unwrap_or(context);
+                tails.clear();
+                tail_counts.clear();
+            }
+        }
+        pending = if pending > 0 { pending - 1 } else { utf8_continuation_len(x).unwrap_or(0) };
+        if !config.stop.is_empty() && generated >= config.stop.len() && buffer.ends_with(&config.stop) {
+            if config.trim_stop {
+                buffer.truncate(buffer.len() - config.stop.len());
+            }
+            break
+        }
+        let ready = buffer.len().saturating_sub(held);
+        if ready > emitted {
+            emit(&buffer[emitted..ready]);
+            emitted = ready;
+        }
+        if emitted > kept + STREAM_CHUNK {
+            buffer.drain(..emitted - kept);
+            emitted = kept;
+        }
+    }
+    emit(&buffer[emitted..]);
+    first
+}
+
+/// The context of a `WordModel` and a `CodepointModel` packs the preceding tokens into a
+/// u64, this many bits each. Just enough for any Unicode scalar value.
+pub const WORD_BITS: u32 = 21;
+/// So they can't remember more than 3 tokens.
+pub const MAX_WORD_ORDER: u8 = (64/WORD_BITS) as u8;
+
+/// Mask that keeps only the last `order` tokens of a `WordModel` or `CodepointModel` context.
+pub fn word_context_mask(order: u8) -> u64 {
+    (1<<(WORD_BITS*order as u32)) - 1
+}
+
+/// Same as `context_push`, but for the contexts of `WordModel` and `CodepointModel`.
+pub fn word_context_push(context: &mut u64, id: u32, order: u8) {
+    *context = ((*context<<WORD_BITS) | id as u64) & word_context_mask(order);
+}
+
+// Words of the text, which are separated by any amount of whitespace
+fn split_words(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.split(|x| x.is_ascii_whitespace()).filter(|word| !word.is_empty())
+}
+
+// Serializes the contexts of `WordModel` and `CodepointModel` ordered by the context
+fn write_wide_contexts(w: &mut impl io::Write, model: &HashMap<u64, Freq<u32>>) -> io::Result<()> {
+    let mut contexts: Vec<(&u64, &Freq<u32>)> = model.iter().collect();
+    contexts.sort_by_key(|(context, _)| **context);
+    w.write_all(&(contexts.len() as u64).to_le_bytes())?;
+    for (context, freq) in contexts {
+        w.write_all(&context.to_le_bytes())?;
+        w.write_all(&(freq.tokens.len() as u32).to_le_bytes())?;
+        for (token, p) in freq.tokens.iter() {
+            w.write_all(&token.to_le_bytes())?;
+            w.write_all(&p.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// Deserializes the contexts written by `write_wide_contexts`, every token has to be `valid`
+fn read_wide_contexts(r: &mut impl io::Read, valid: impl Fn(u32) -> bool) -> io::Result<HashMap<u64, Freq<u32>>> {
+    let mut result = HashMap::new();
+    let count = read_u64(r)?;
+    for _ in 0..count {
+        let context = read_u64(r)?;
+        let mut freq = Freq::new();
+        for _ in 0..read_u32(r)? {
+            let token = read_u32(r)?;
+            if !valid(token) {
+                return Err(corrupt_model());
+            }
+            freq.tokens.push((token, read_u32(r)?));
+        }
+        result.insert(context, freq);
+    }
+    Ok(result)
+}
+
+// The prompt of `generate_wide`, already turned into the tokens of the model
+struct WidePrompt {
+    // The last tokens of the prompt
+    context: u64,
+    tokens: Vec<u32>,
+    // The text of the prompt
+    buffer: Vec<u8>,
+}
+
+// The generation loop of `generate_words` and `generate_codepoints`. Continues the prompt,
+// appending every new token to its text with `emit`, and returns that text.
+fn generate_wide(model: &HashMap<u64, Freq<u32>>, order: u8, config: &GenConfig, prompt: WidePrompt, emit: impl Fn(&mut Vec<u8>, u32), rng: &mut impl Rng) -> Vec<u8> {
+    let WidePrompt {mut context, mut tokens, mut buffer} = prompt;
+    for _ in 0..config.limit {
+        let recent = &tokens[tokens.len().saturating_sub(REPETITION_WINDOW)..];
+        let token = model.get(&context).and_then(|freq| {
+            pick_token(&truncate_tokens(Cow::Borrowed(freq), config), config, recent, rng)
+        });
+        let Some(token) = token else {
+            break
+        };
+        emit(&mut buffer, token);
+        tokens.push(token);
+        word_context_push(&mut context, token, order);
+    }
+    buffer
+}
+
+/// Same as `Model`, but the tokens are the ids of whole words rather than bytes.
+#[derive(Debug)]
+pub struct WordModel {
+    /// Amount of preceding words that make up a context
+    pub order: u8,
+    /// Every known word, the id of a word is its index. Id 0 is the padding of the contexts
+    /// at the very beginning of the text, so it's always an empty word.
+    pub vocabulary: Vec<Vec<u8>>,
+    /// Frequencies of the ids of the words that followed every known context
+    pub model: HashMap<u64, Freq<u32>>,
+    /// Same as `Model::preprocessing`
+    pub preprocessing: Preprocessing,
+    /// Same as `Model::metadata`
+    pub metadata: Metadata,
+    // Id of every word of `vocabulary`
+    ids: HashMap<Vec<u8>, u32>,
+}
+
+impl WordModel {
+    pub fn new(order: u8) -> Self {
+        Self {
+            order,
+            vocabulary: vec![Vec::new()],
+            model: HashMap::new(),
+            preprocessing: Preprocessing::default(),
+            metadata: Metadata::default(),
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Id of `word`, None if it's not in the vocabulary.
+    pub fn id(&self, word: &[u8]) -> Option<u32> {
+        self.ids.get(word).copied()
+    }
+
+    fn intern(&mut self, word: &[u8]) -> Result<u32, String> {
+        if let Some(id) = self.id(word) {
+            return Ok(id)
+        }
+        let id = self.vocabulary.len() as u32;
+        if id >= 1<<WORD_BITS {
+            return Err(format!("more than {} distinct words, the ids don't fit into {WORD_BITS} bits", (1<<WORD_BITS) - 1));
+        }
+        self.vocabulary.push(word.to_vec());
+        self.ids.insert(word.to_vec(), id);
+        Ok(id)
+    }
+
+    /// Records every word of `bytes` `weight` times along with the words that preceded it.
+    /// Fails if there are too many distinct words to give each of them an id.
+    pub fn train(&mut self, bytes: &[u8], weight: u32) -> Result<(), String> {
+        let mut context = 0;
+        for word in split_words(bytes) {
+            let id = self.intern(word)?;
+            self.model.entry(context).or_default().push_n(id, weight);
+            word_context_push(&mut context, id, self.order);
+        }
+        Ok(())
+    }
+
+    /// Serializes the model in the format `read_from` understands. Same as `Model::write_to`,
+    /// but with the vocabulary right after the header.
+    pub fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let mut w = Checksum::new(w);
+        write_header(&mut w, ModelKind::Words, self.order, self.preprocessing, &self.metadata)?;
+        w.write_all(&(self.vocabulary.len() as u64 - 1).to_le_bytes())?;
+        for word in self.vocabulary.iter().skip(1) {
+            w.write_all(&(word.len() as u32).to_le_bytes())?;
+            w.write_all(word)?;
+        }
+        write_wide_contexts(&mut w, &self.model)?;
+        let hash = w.hash;
+        w.inner.write_all(&hash.to_le_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Deserializes the model written by `write_to`.
+    pub fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
+        let mut r = Checksum::new(r);
+        let ModelInfo { version, kind, order, preprocessing, metadata } = read_header(&mut r)?;
+        expect_kind(kind, ModelKind::Words)?;
+        if !(1..=MAX_WORD_ORDER).contains(&order) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported word model order {order}")));
+        }
+        let mut result = Self::new(order);
+        result.preprocessing = preprocessing;
+        result.metadata = metadata.unwrap_or_default();
+        read_checked(&mut r, version, |r| {
+            let words = read_u64(r)?;
+            for _ in 0..words {
+                let len = read_u32(r)?;
+                let word = read_bytes(r, len as u64)?;
+                result.intern(&word).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+            let vocabulary_len = result.vocabulary.len();
+            result.model = read_wide_contexts(r, |id| (id as usize) < vocabulary_len)?;
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+/// Same as `generate`, but for the word-level models. `GenConfig::limit` and the window of
+/// `GenConfig::repetition_penalty` count words instead of bytes. Only the prompt and the
+/// sampling options make sense here, the rest of the config is ignored. The words of the
+/// result are separated by spaces.
+pub fn generate_words(model: &WordModel, config: &GenConfig, rng: &mut impl Rng) -> Vec<u8> {
+    let mut context = 0;
+    let mut ids = Vec::new();
+    let mut buffer = Vec::new();
+    let push_word = |buffer: &mut Vec<u8>, word: &[u8]| {
+        if !buffer.is_empty() {
+            buffer.push(b' ');
+        }
+        buffer.extend_from_slice(word);
+    };
+    for word in split_words(config.prompt.as_bytes()) {
+        push_word(&mut buffer, word);
+        match model.id(word) {
+            Some(id) => {
+                ids.push(id);
+                word_context_push(&mut context, id, model.order);
+            }
+            // Nothing is known about what follows an unknown word, start as if it's a new text
+            None => context = 0,
+        }
+    }
+    let prompt = WidePrompt {context, tokens: ids, buffer};
+    generate_wide(&model.model, model.order, config, prompt, |buffer, id| {
+        push_word(buffer, &model.vocabulary[id as usize])
+    }, rng)
+}
+
+/// Same as `Model`, but the tokens are Unicode scalar values rather than bytes, so it
+/// never produces invalid UTF-8.
+#[derive(Debug)]
+pub struct CodepointModel {
+    /// Amount of preceding codepoints that make up a context
+    pub order: u8,
+    /// Frequencies of the codepoints that followed every known context
+    pub model: HashMap<u64, Freq<u32>>,
+    /// Same as `Model::preprocessing`
+    pub preprocessing: Preprocessing,
+    /// Same as `Model::metadata`
+    pub metadata: Metadata,
+}
+
+impl CodepointModel {
+    pub fn new(order: u8) -> Self {
+        Self {
+            order,
+            model: HashMap::new(),
+            preprocessing: Preprocessing::default(),
+            metadata: Metadata::default(),
+        }
+    }
+
+    /// Records every codepoint of `bytes` `weight` times along with the codepoints that
+    /// preceded it. Invalid UTF-8 is recorded as U+FFFD.
+    pub fn train(&mut self, bytes: &[u8], weight: u32) {
+        let mut context = 0;
+        for c in String::from_utf8_lossy(bytes).chars() {
+            self.model.entry(context).or_default().push_n(c as u32, weight);
+            word_context_push(&mut context, c as u32, self.order);
+        }
+    }
+
+    /// Serializes the model in the format `read_from` understands.
+    pub fn write_to(&self, w: &mut impl io::Write) -> io::Result<()> {
+        let mut w = Checksum::new(w);
+        write_header(&mut w, ModelKind::Codepoints, self.order, self.preprocessing, &self.metadata)?;
+        write_wide_contexts(&mut w, &self.model)?;
+        let hash = w.hash;
+        w.inner.write_all(&hash.to_le_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Deserializes the model written by `write_to`.
+    pub fn read_from(r: &mut impl io::Read) -> io::Result<Self> {
+        let mut r = Checksum::new(r);
+        let ModelInfo { version, kind, order, preprocessing, metadata } = read_header(&mut r)?;
+        expect_kind(kind, ModelKind::Codepoints)?;
+        if !(1..=MAX_WORD_ORDER).contains(&order) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported codepoint model order {order}")));
+        }
+        let mut result = Self::new(order);
+        result.preprocessing = preprocessing;
+        result.metadata = metadata.unwrap_or_default();
+        read_checked(&mut r, version, |r| {
+            result.model = read_wide_contexts(r, |c| char::from_u32(c).is_some())?;
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+/// Same as `generate`, but for the codepoint-level models. `GenConfig::limit` and the window
+/// of `GenConfig::repetition_penalty` count codepoints instead of bytes. Only the prompt
+/// and the sampling options make sense here, the rest of the config is ignored.
+pub fn generate_codepoints(model: &CodepointModel, config: &GenConfig, rng: &mut impl Rng) -> Vec<u8> {
+    let mut context = 0;
+    let mut codepoints = Vec::new();
+    for c in config.prompt.chars() {
+        codepoints.push(c as u32);
+        word_context_push(&mut context, c as u32, model.order);
+    }
+    let prompt = WidePrompt {context, tokens: codepoints, buffer: config.prompt.as_bytes().to_vec()};
+    generate_wide(&model.model, model.order, config, prompt, |buffer, c| {
+        // `read_from` makes sure all the tokens are valid
+        let c = char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER);
+        buffer.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }, rng)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn train_config() -> TrainConfig {
+        TrainConfig {
+            threads: 1,
+            per_line: false,
+            eos: false,
+            weight: 1,
+            max_records: None,
+            skip_warmup: false,
+            bos: false,
+        }
+    }
+
+    fn trained(order: u8, text: &[u8]) -> Model {
+        let mut model = Model::new(order);
+        model.train(text, &train_config());
+        model
+    }
+
+    // Writes `model` and reads it back
+    fn model_round_trip(model: &Model) -> Model {
+        let mut bytes = Vec::new();
+        model.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        Model::read_from(&mut bytes.as_slice()).expect("A freshly written model should be readable")
+    }
+
+    fn assert_same_model(a: &Model, b: &Model) {
+        assert_eq!(a.order, b.order);
+        assert_eq!(a.preprocessing, b.preprocessing);
+        assert_eq!(a.metadata, b.metadata);
+        assert_eq!(a.model.len(), b.model.len());
+        for (context, freq) in a.model.iter() {
+            let other = b.model.get(context).unwrap_or_else(|| panic!("context {:#x} is lost", context));
+            assert_eq!(freq.tokens, other.tokens, "context {context:#x}");
+        }
+    }
+
+    // Writes `freq` and reads it back
+    fn freq_round_trip(freq: &Freq) -> Freq {
+        let mut bytes = Vec::new();
+        freq.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        Freq::read_from(&mut bytes.as_slice()).expect("A freshly written Freq should be readable")
+    }
+
+    #[test]
+    fn freq_round_trip_keeps_the_tokens() {
+        let freq = Freq { tokens: vec![(0, 0), (b'a', 1), (b'b', 69), (255, u32::MAX)] };
+        assert_eq!(freq_round_trip(&freq).tokens, freq.tokens);
+    }
+
+    #[test]
+    fn freq_round_trip_keeps_all_256_tokens() {
+        let freq = Freq { tokens: (0..=255).map(|x| (x, x as u32*1000 + 1)).collect() };
+        assert_eq!(freq_round_trip(&freq).tokens, freq.tokens);
+    }
+
+    #[test]
+    fn empty_freq_is_not_written() {
+        let mut bytes = Vec::new();
+        let err = Freq::new().write_to(&mut bytes).expect_err("An empty Freq should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn truncated_freq_is_not_read() {
+        let mut bytes = Vec::new();
+        Freq { tokens: vec![(b'a', 1), (b'b', 2)] }.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        bytes.pop();
+        assert!(Freq::read_from(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn model_round_trip_keeps_every_context() {
+        let mut model = trained(3, b"the quick brown fox jumps over the lazy dog, then the dog jumps back");
+        model.preprocessing.lowercase = true;
+        model.metadata.add_training("fox.txt", &train_config());
+        model.metadata.created = 1700000000;
+        assert_same_model(&model_round_trip(&model), &model);
+    }
+
+    #[test]
+    fn model_round_trip_of_an_empty_model() {
+        let model = Model::new(5);
+        let result = model_round_trip(&model);
+        assert!(result.model.is_empty());
+        assert_same_model(&result, &model);
+    }
+
+    #[test]
+    fn model_round_trip_of_a_single_context() {
+        let mut model = Model::new(2);
+        model.push_n(0x6162, b'c', u32::MAX);
+        assert_same_model(&model_round_trip(&model), &model);
+    }
+
+    #[test]
+    fn corrupted_model_is_not_read() {
+        let mut bytes = Vec::new();
+        trained(2, b"abracadabra").write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        let middle = bytes.len()/2;
+        bytes[middle] ^= 1;
+        assert!(Model::read_from(&mut bytes.as_slice()).is_err());
+    }
+
+    // Draws from `sample` many times and checks that every token of `freq` comes up about
+    // count/total of the time, and nothing else ever does
+    fn assert_follows(freq: &Freq, mut sample: impl FnMut() -> Option<u8>) {
+        const DRAWS: usize = 100_000;
+        let mut seen = [0usize; 256];
+        for _ in 0..DRAWS {
+            seen[sample().expect("A non-empty Freq always has something to pick") as usize] += 1;
+        }
+        for x in 0..=255u8 {
+            let expected = freq.probability(x).unwrap_or(0.0);
+            let actual = seen[x as usize] as f64/DRAWS as f64;
+            assert!((actual - expected).abs() < 0.01, "{:?}: byte {} came up {} of the time instead of {}", freq.tokens, x, actual, expected);
+        }
+    }
+
+    // Freqs of up to 16 distinct bytes with the counts up to 1000, some of them 0
+    fn random_freqs(rng: &mut LCG) -> Vec<Freq> {
+        (0..32).map(|_| {
+            let mut freq = Freq::new();
+            for _ in 0..1 + rng.below(16) {
+                freq.push_n(rng.below(256) as u8, rng.below(1001) as u32);
+            }
+            // At least one of them has to be possible
+            freq.push(rng.below(256) as u8);
+            freq
+        }).collect()
+    }
+
+    #[test]
+    fn freq_random_follows_the_counts() {
+        let mut rng = LCG::new(69);
+        for freq in random_freqs(&mut rng) {
+            let mut sampler = LCG::new(420);
+            assert_follows(&freq, || freq.random(&mut sampler));
+        }
+    }
+
+    #[test]
+    fn alias_table_follows_the_counts() {
+        let mut rng = LCG::new(1337);
+        for freq in random_freqs(&mut rng) {
+            let table = AliasTable::new(&freq);
+            let mut sampler = LCG::new(420);
+            assert_follows(&freq, || table.random(&mut sampler));
+        }
+        // Only the zero counts, which the imported models may have
+        let freq = Freq { tokens: vec![(b'x', 0), (b'y', 0)] };
+        assert_eq!(freq.random(&mut rng), None);
+        assert_eq!(AliasTable::new(&freq).random(&mut rng), None);
+    }
+
+    #[test]
+    fn add_count_saturates() {
+        let mut count = u32::MAX - 1;
+        add_count(&mut count, 1);
+        assert_eq!(count, u32::MAX);
+        add_count(&mut count, u32::MAX);
+        assert_eq!(count, u32::MAX);
+    }
+
+    #[test]
+    fn freq_push_saturates() {
+        let mut freq = Freq::new();
+        freq.push_n(b'a', u32::MAX - 1);
+        freq.push(b'a');
+        freq.push(b'a');
+        assert_eq!(freq.tokens, vec![(b'a', u32::MAX)]);
+    }
+
+    // Hands the bytes over one at a time, like a slow pipe may
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = std::cmp::min(1, std::cmp::min(buf.len(), self.0.len()));
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn model_is_read_one_byte_at_a_time() {
+        let model = trained(4, b"she sells sea shells by the sea shore");
+        let mut bytes = Vec::new();
+        model.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        let result = Model::read_from(&mut Trickle(&bytes)).expect("Short reads should be retried");
+        assert_same_model(&result, &model);
+    }
+
+    // Takes the bytes one at a time, like a slow pipe may
+    struct Dribble(Vec<u8>);
+
+    impl Write for Dribble {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = std::cmp::min(1, buf.len());
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn model_is_written_one_byte_at_a_time() {
+        let model = trained(4, b"she sells sea shells by the sea shore");
+        let mut sink = Dribble(Vec::new());
+        model.write_to(&mut sink).expect("Short writes should be retried");
+        let result = Model::read_from(&mut sink.0.as_slice()).expect("The written model should be read back");
+        assert_same_model(&result, &model);
+    }
+
+    #[test]
+    fn same_training_writes_the_same_bytes() {
+        let text: Vec<u8> = b"peter piper picked a peck of pickled peppers\n".repeat(50);
+        let written = |threads: usize| {
+            let mut model = Model::new(5);
+            model.train(&text, &TrainConfig {threads, ..train_config()});
+            let mut bytes = Vec::new();
+            model.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+            bytes
+        };
+        let bytes = written(1);
+        assert_eq!(written(1), bytes);
+        assert_eq!(written(4), bytes);
+    }
+
+    fn gen_config() -> GenConfig {
+        GenConfig {
+            limit: 200,
+            prompt: String::new(),
+            flatten_counts: false,
+            utf8_boundary: false,
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            stop: Vec::new(),
+            trim_stop: false,
+            min_length: 0,
+            eos: false,
+            backoff: true,
+            smoothing: 0.0,
+            random_start: false,
+            greedy: false,
+            beam: None,
+            repetition_penalty: 1.0,
+            cycle_guard: true,
+            word_limit: 0,
+            used_starts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn zero_temperature_is_argmax() {
+        let mut rng = LCG::new(69);
+        let mut freqs = random_freqs(&mut rng);
+        // A tie of the most frequent ones
+        freqs.push(Freq { tokens: vec![(b'z', 5), (b'a', 5), (b'm', 1)] });
+        for freq in freqs {
+            assert_eq!(freq.random_with_temperature(&mut rng, 0.0), freq.argmax(), "{:?}", freq.tokens);
+        }
+
+        let model = trained(3, b"to be or not to be, that is the question");
+        // Same seed and no backoff or cycle guard, so only greedy vs temperature 0 differs
+        let config = GenConfig {backoff: false, cycle_guard: false, ..gen_config()};
+        let greedy = generate(&model, &GenConfig {greedy: true, ..config.clone()}, &mut LCG::new(1));
+        let cold = generate(&model, &GenConfig {temperature: 0.0, ..config}, &mut LCG::new(1));
+        assert_eq!(cold, greedy);
+    }
+
+    #[test]
+    fn below_is_uniform() {
+        const DRAWS: usize = 300_000;
+        let mut rng = LCG::new(69);
+        // Small ones, and the ones just above a half of the range, where `x % n` would make
+        // the first third of the results twice as likely as the rest
+        for (n, step) in [(6, 1), (3<<30, 1<<30), (3<<62, 1<<62)] {
+            let buckets = (n/step) as usize;
+            let mut seen = vec![0usize; buckets];
+            for _ in 0..DRAWS {
+                let x = rng.below(n);
+                assert!(x < n);
+                seen[(x/step) as usize] += 1;
+            }
+            for (i, count) in seen.iter().enumerate() {
+                let actual = *count as f64/DRAWS as f64;
+                let expected = 1.0/buckets as f64;
+                assert!((actual - expected).abs() < 0.01, "below({}): {} of the results are in bucket {} instead of {}", n, actual, i, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn order_3_model_generates_text() {
+        let text = b"how much wood would a woodchuck chuck if a woodchuck could chuck wood";
+        let model = trained(3, text);
+        // Nothing to jump around with, so the whole text comes from the contexts of order 3
+        let config = GenConfig {backoff: false, cycle_guard: false, ..gen_config()};
+        let result = generate(&model, &config, &mut LCG::new(69));
+        assert!(!result.is_empty());
+        // Every byte has to follow the 3 bytes before it somewhere in the text
+        let padded: Vec<u8> = [0, 0, 0].iter().chain(result.iter()).copied().collect();
+        for window in padded.windows(4) {
+            let context = window[..3].iter().fold(0, |context, x| context<<8 | *x as u64);
+            assert!(model.model.get(&context).and_then(|freq| freq.probability(window[3])).is_some(), "{:?}", window);
+        }
+
+        // A prompt longer than the order is masked down to its last 3 bytes, which are known
+        let config = GenConfig {prompt: String::from("a woodchuck"), ..gen_config()};
+        let result = generate(&model, &config, &mut LCG::new(69));
+        assert!(result.len() > config.prompt.len());
+    }
+
+    // `model` the way the original carrotson wrote it, without a header and a checksum
+    fn legacy_bytes(model: &Model) -> Vec<u8> {
+        let mut bytes = (model.model.len() as u64).to_le_bytes().to_vec();
+        for (context, freq) in model.sorted() {
+            bytes.extend_from_slice(&context.to_le_bytes());
+            freq.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        }
+        bytes
+    }
+
+    #[test]
+    fn legacy_model_is_read() {
+        let mut model = trained(MAX_ORDER, b"the original carrotson had no header");
+        // Its amount of tokens wraps around to 0
+        for x in 0..=255 {
+            model.push(0x6361_7272_6f74_736f, x);
+        }
+        let result = Model::read_legacy(&mut legacy_bytes(&model).as_slice()).expect("A legacy model should be readable");
+        assert_same_model(&result, &model);
+    }
+
+    #[test]
+    fn legacy_model_with_garbage_is_not_read() {
+        let model = trained(MAX_ORDER, b"abracadabra");
+        let mut bytes = legacy_bytes(&model);
+        bytes.push(0);
+        assert!(Model::read_legacy(&mut bytes.as_slice()).is_err());
+        bytes.truncate(bytes.len() - 2);
+        assert!(Model::read_legacy(&mut bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn model_format_is_sniffed() {
+        let model = trained(MAX_ORDER, b"old and new models are read alike");
+        let mut bytes = Vec::new();
+        model.write_to(&mut bytes).expect("Writing into a Vec can't fail");
+        assert_same_model(&Model::read_from(&mut bytes.as_slice()).expect("A current model should be readable"), &model);
+        let result = Model::read_from(&mut legacy_bytes(&model).as_slice()).expect("A legacy model should be readable");
+        assert_same_model(&result, &model);
+    }
+
+    #[test]
+    fn random_bytes_are_not_a_model() {
+        let mut rng = LCG::new(69);
+        for len in [0, 7, 8, 520, 1040, 4096] {
+            let bytes: Vec<u8> = (0..len).map(|_| rng.next_u32() as u8).collect();
+            let err = Model::read_from(&mut bytes.as_slice()).expect_err("Random bytes should be rejected");
+            // Too short to even have the magic
+            if len >= 4 {
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{} bytes", len);
+            }
+        }
+    }
+}